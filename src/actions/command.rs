@@ -1,81 +1,374 @@
-use crate::actions::Action;
-use notify::Event;
+extern crate shell_words;
+
+use crate::actions::process::{self, OutputCapture};
+use crate::actions::template::{cmd_quoting, no_quoting, posix_quoting, Template};
+use crate::actions::{self, Action, CancelToken, Change, Execution, OUTPUT_LIMIT};
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::io::{self, Write};
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a running command is checked for having exited or having been
+/// cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait for the rest of the output after a command exited.
+/// Processes it started in the background can keep its output open.
+const OUTPUT_WAIT: Duration = Duration::from_millis(500);
+
+/// How the command line of a `CommandAction` is run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Shell {
+    /// Split the command line into arguments the way a POSIX shell would,
+    /// honoring quotes and backslash escapes, and run the program directly.
+    #[default]
+    None,
+    /// Run the command line with the platform's shell, `sh -c` or `cmd /C`.
+    Default,
+    /// Run the command line with the given shell program and `-c`.
+    Program(String),
+}
 
 pub struct CommandAction {
     command_line: String,
+    template: Template,
     quiet: bool,
+    shell: Shell,
+    timeout: Option<Duration>,
+    grace_period: Duration,
+    env: BTreeMap<String, String>,
+    env_clear: bool,
+    cwd: Option<PathBuf>,
 }
 
 impl CommandAction {
-    pub fn new(command_line: String, quiet: bool) -> CommandAction {
-        CommandAction {
+    /// Creates a command action, failing if the command line contains an
+    /// unknown or unclosed placeholder.
+    pub fn new(command_line: String, quiet: bool) -> Result<CommandAction> {
+        let template = match Template::parse(&command_line) {
+            Ok(template) => template,
+            Err(message) => {
+                return Err(Error::InvalidCommand {
+                    command: command_line,
+                    message,
+                })
+            }
+        };
+
+        Ok(CommandAction {
             command_line,
+            template,
             quiet,
+            shell: Shell::None,
+            timeout: None,
+            grace_period: Duration::from_secs(5),
+            env: BTreeMap::new(),
+            env_clear: false,
+            cwd: None,
+        })
+    }
+
+    /// Sets how the command line is run. Pipes, redirects and `&&` only
+    /// work when it's run with a shell.
+    pub fn set_shell(&mut self, shell: Shell) {
+        self.shell = shell;
+    }
+
+    /// Checks that the command line can be split into arguments when it's
+    /// run without a shell, so unbalanced quotes are found before the first
+    /// change. A shell reports problems with the command line itself.
+    pub fn validate(&self) -> Result<()> {
+        if self.shell == Shell::None {
+            self.words()?;
         }
+
+        Ok(())
+    }
+
+    /// Sets how long the command may run. When it runs for longer, its
+    /// process group is sent SIGTERM, and killed if it's still running after
+    /// the grace period. By default, there is no timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
     }
 
-    pub fn get_command_line(&self, event: &Event) -> String {
-        let path = if event.paths.is_empty() {
-            ""
+    /// Sets how long to wait for a timed out command to exit after sending
+    /// it SIGTERM, before killing it.
+    pub fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Sets environment variables for the command, in addition to the ones
+    /// eagle was started with.
+    pub fn set_env(&mut self, env: BTreeMap<String, String>) {
+        self.env = env;
+    }
+
+    /// Starts the command without the environment variables eagle was
+    /// started with, so it only gets the ones set with `set_env` and the
+    /// `EAGLE_` variables. Note that this includes `PATH`.
+    pub fn set_env_clear(&mut self, env_clear: bool) {
+        self.env_clear = env_clear;
+    }
+
+    /// Sets the directory the command runs in. By default, it runs in the
+    /// working directory of eagle.
+    pub fn set_cwd(&mut self, cwd: PathBuf) {
+        self.cwd = Some(cwd);
+    }
+
+    /// Returns the command line as it was given, with the placeholders.
+    pub fn command_line(&self) -> &str {
+        &self.command_line
+    }
+
+    /// Returns the command line with the placeholders replaced by the
+    /// details of the change, quoted so that the shell the command runs
+    /// with sees each one as a single word, or as part of the quotes they
+    /// are written inside of.
+    pub fn get_command_line(&self, change: &Change) -> String {
+        if self.shell_program() == Some("cmd") {
+            self.template.render(change, cmd_quoting)
         } else {
-            event.paths[0].to_str().unwrap_or("")
+            self.template.render(change, posix_quoting)
+        }
+    }
+
+    /// Builds the command to run for a change. Without a shell, the command
+    /// line is split into words before the placeholders are replaced, so a
+    /// path with spaces or quotes in it stays a single argument. A word that
+    /// is just `{:paths}` becomes one argument per changed path.
+    ///
+    /// Besides the environment variables set for it, the command gets
+    /// `EAGLE_` variables with the details of the change.
+    pub fn get_command(&self, change: &Change) -> Result<Command> {
+        let mut command = self.build_command(change)?;
+        if self.env_clear {
+            command.env_clear();
+        }
+        command.envs(&self.env);
+        command.envs(change_env(change));
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+
+        Ok(command)
+    }
+
+    fn shell_program(&self) -> Option<&str> {
+        match &self.shell {
+            Shell::None => None,
+            Shell::Default if cfg!(windows) => Some("cmd"),
+            Shell::Default => Some("sh"),
+            Shell::Program(program) => Some(program.as_str()),
+        }
+    }
+
+    fn build_command(&self, change: &Change) -> Result<Command> {
+        if let Some(shell_program) = self.shell_program() {
+            let flag = if shell_program == "cmd" { "/C" } else { "-c" };
+            let mut command = Command::new(shell_program);
+            command.arg(flag).arg(self.get_command_line(change));
+            return Ok(command);
+        }
+
+        let mut args: Vec<OsString> = vec![];
+        for word in self.words()? {
+            let template = match Template::parse(&word) {
+                Ok(template) => template,
+                Err(message) => return Err(self.invalid(message)),
+            };
+
+            if template.is_all_paths() {
+                args.extend(change.paths.iter().map(|path| path.into()));
+            } else {
+                args.push(template.render(change, no_quoting).into());
+            }
+        }
+
+        let mut args = args.into_iter();
+        let mut command = match args.next() {
+            Some(program) => Command::new(program),
+            None => return Err(self.invalid("empty command line".to_string())),
         };
+        command.args(args);
 
-        self.command_line.replace("{:p}", path)
+        Ok(command)
     }
 
-    pub fn get_command(&self, event: &Event) -> Command {
-        let command_line = self.get_command_line(event);
-        let mut cmd_pieces = command_line.split(' ');
-        let mut command = Command::new(cmd_pieces.next().unwrap());
-        for piece in cmd_pieces {
-            command.arg(piece);
+    /// Waits for a running command to exit, stopping it when it's cancelled
+    /// or runs into the timeout.
+    fn wait(&self, child: &mut Child, cancel: &CancelToken) -> Result<()> {
+        let deadline = self
+            .timeout
+            .map(|timeout| (timeout, Instant::now() + timeout));
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) if status.success() => return Ok(()),
+                Ok(Some(status)) => {
+                    return Err(Error::ActionExitStatus {
+                        command: self.command_line.clone(),
+                        status,
+                    })
+                }
+                Ok(None) => {}
+                Err(source) => {
+                    return Err(Error::ActionSpawn {
+                        command: self.command_line.clone(),
+                        source,
+                    })
+                }
+            }
+
+            if cancel.is_cancelled() {
+                process::stop(child, process::terminate_signal(), self.grace_period);
+                if !self.quiet {
+                    println!("Cancelled command: {:?}", self.command_line);
+                }
+                return Err(Error::Cancelled {
+                    command: self.command_line.clone(),
+                });
+            }
+
+            if let Some((timeout, deadline)) = deadline {
+                if Instant::now() >= deadline {
+                    return Err(self.stop_timed_out(child, timeout));
+                }
+            }
+
+            thread::sleep(POLL_INTERVAL);
         }
+    }
 
-        command
+    /// Stops a command that ran for longer than its timeout.
+    fn stop_timed_out(&self, child: &mut Child, timeout: Duration) -> Error {
+        println!(
+            "Command timed out after {:?}: {:?}",
+            timeout, self.command_line
+        );
+        let signal = process::terminate_signal();
+        if process::stop(child, signal, self.grace_period) {
+            println!(
+                "Command did not exit within {:?}, killed it.",
+                self.grace_period
+            );
+        }
+
+        Error::Timeout {
+            command: self.command_line.clone(),
+            timeout,
+        }
+    }
+
+    fn words(&self) -> Result<Vec<String>> {
+        shell_words::split(&self.command_line).map_err(|error| self.invalid(error.to_string()))
+    }
+
+    fn invalid(&self, message: String) -> Error {
+        Error::InvalidCommand {
+            command: self.command_line.clone(),
+            message,
+        }
     }
 }
 
 impl Action for CommandAction {
-    fn handle_change(&self, event: &Event) -> Result<(), &'static str> {
-        let mut command = self.get_command(event);
-
-        if !self.quiet {
-            command
-                .stdin(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit());
-        }
+    fn handle_change(&self, change: &Change) -> Result<()> {
+        self.execute(change, &mut Execution::default())
+    }
 
-        let command_result = command.output();
+    /// Runs the command and waits for it to exit, capturing the end of its
+    /// output. If the execution is cancelled while it's running, the command
+    /// and any processes it started are killed. If it runs for longer than
+    /// the timeout, it's stopped and `Error::Timeout` is returned.
+    fn execute(&self, change: &Change, execution: &mut Execution) -> Result<()> {
+        let mut command = match self.get_command(change) {
+            Ok(command) => command,
+            Err(error) => {
+                println!("{}", error);
+                return Err(error);
+            }
+        };
 
-        match command_result {
-            Err(_) => {
+        // The command runs in its own process group, which would be stopped
+        // if it tried to read from the terminal.
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = match process::spawn_in_group(&mut command) {
+            Ok(child) => child,
+            Err(source) => {
                 println!("Could not execute command: {:?}", self.command_line);
-                Err("Could not execute command")
+                return Err(Error::ActionSpawn {
+                    command: self.command_line.clone(),
+                    source,
+                });
             }
-            Ok(output) => {
-                if !self.quiet {
-                    println!("{}", String::from_utf8_lossy(&output.stdout));
-
-                    if !output.stderr.is_empty() {
-                        let write_result = writeln!(
-                            &mut io::stderr(),
-                            "{}",
-                            String::from_utf8_lossy(&output.stderr)
-                        );
-                        if let Err(x) = write_result {
-                            println!("Error: Unable to write to stderr: {}", x);
-                        }
-                    }
-                }
+        };
 
-                Ok(())
-            }
+        let stdout = child.stdout.take().map(|pipe| {
+            let echo = (!self.quiet).then(|| Box::new(io::stdout()) as Box<dyn Write + Send>);
+            OutputCapture::start(pipe, echo, OUTPUT_LIMIT)
+        });
+        let stderr = child.stderr.take().map(|pipe| {
+            let echo = (!self.quiet).then(|| Box::new(io::stderr()) as Box<dyn Write + Send>);
+            OutputCapture::start(pipe, echo, OUTPUT_LIMIT)
+        });
+
+        let result = self.wait(&mut child, &execution.cancel);
+
+        execution.exit_status = child.try_wait().ok().flatten();
+        if let Some(stdout) = stdout {
+            execution.stdout = stdout.finish(OUTPUT_WAIT);
+        }
+        if let Some(stderr) = stderr {
+            execution.stderr = stderr.finish(OUTPUT_WAIT);
+        }
+
+        result
+    }
+
+    fn describe(&self) -> String {
+        format!("command {:?}", self.command_line)
+    }
+}
+
+/// Returns the `EAGLE_` environment variables for a change:
+/// `EAGLE_PATH` is the first changed path, `EAGLE_CHANGED_PATHS` all of
+/// them separated by newlines, `EAGLE_WATCH_ROOT` the watched path they were
+/// found under and `EAGLE_EVENT_KIND` the kind of change, like `modify`.
+fn change_env(change: &Change) -> Vec<(&'static str, OsString)> {
+    let path = change.paths.first().cloned().unwrap_or_default();
+    let empty = Path::new("");
+    let (old_path, new_path) = change.first_rename().unwrap_or((empty, empty));
+    let mut changed_paths = OsString::new();
+    for (index, path) in change.paths.iter().enumerate() {
+        if index > 0 {
+            changed_paths.push("\n");
         }
+        changed_paths.push(path);
     }
+
+    vec![
+        ("EAGLE_PATH", path.into_os_string()),
+        ("EAGLE_CHANGED_PATHS", changed_paths),
+        (
+            "EAGLE_WATCH_ROOT",
+            change.watch_root.clone().into_os_string(),
+        ),
+        (
+            "EAGLE_EVENT_KIND",
+            actions::event_kind_name(&change.kind).into(),
+        ),
+        ("EAGLE_OLD_PATH", old_path.as_os_str().to_owned()),
+        ("EAGLE_NEW_PATH", new_path.as_os_str().to_owned()),
+    ]
 }
 
 #[cfg(test)]
@@ -85,12 +378,18 @@ mod test {
     use super::*;
 
     use crate::actions::Action;
-    use notify::{event, Event, EventKind};
-    use std::path::PathBuf;
+    use notify::{event, EventKind};
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn constructor() {
-        let _ = CommandAction::new("date".to_string(), false);
+        let _ = CommandAction::new("date".to_string(), false).unwrap();
+    }
+
+    #[test]
+    fn constructor_unknown_placeholder() {
+        assert!(CommandAction::new("echo {:pth}".to_string(), false).is_err());
     }
 
     #[test]
@@ -98,11 +397,11 @@ mod test {
         let event_kind = EventKind::Modify(event::ModifyKind::Any);
         let path_buf = PathBuf::from("/");
 
-        let event = Event::new(event_kind).add_path(path_buf);
+        let change = Change::new(event_kind, vec![path_buf], PathBuf::from("/"));
 
         // Assume the "date" command exists on all platforms
-        let command = CommandAction::new("date".to_string(), true);
-        let result = command.handle_change(&event);
+        let command = CommandAction::new("date".to_string(), true).unwrap();
+        let result = command.handle_change(&change);
 
         // We can't capture the output, so just make sure the function
         // returns Ok.
@@ -113,12 +412,259 @@ mod test {
     fn handle_change_missing_command() {
         let event_kind = EventKind::Modify(event::ModifyKind::Any);
         let path_buf = PathBuf::from("/");
-        let event = Event::new(event_kind).add_path(path_buf);
+        let change = Change::new(event_kind, vec![path_buf], PathBuf::from("/"));
 
         // Assume this command does not exist
-        let command = CommandAction::new("command_does_not_exist".to_string(), true);
-        let result = command.handle_change(&event);
+        let command = CommandAction::new("command_does_not_exist".to_string(), true).unwrap();
+        let result = command.handle_change(&change);
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn get_command_splits_quoted_arguments() {
+        let change = Change::new(
+            EventKind::Any,
+            vec![PathBuf::from("/tmp/a b.txt")],
+            PathBuf::from("/"),
+        );
+        let command = CommandAction::new(r#"cp "{:p}" 'my dir/' a\ b"#.to_string(), true).unwrap();
+
+        let command = command.get_command(&change).unwrap();
+        assert_eq!("cp", command.get_program());
+        let args: Vec<&OsStr> = command.get_args().collect();
+        assert_eq!(vec!["/tmp/a b.txt", "my dir/", "a b"], args);
+    }
+
+    #[test]
+    fn get_command_unquoted_path_with_spaces() {
+        let change = Change::new(
+            EventKind::Any,
+            vec![PathBuf::from("/tmp/a b.txt")],
+            PathBuf::from("/"),
+        );
+        let command = CommandAction::new("ls -l {:p}".to_string(), true).unwrap();
+
+        let command = command.get_command(&change).unwrap();
+        let args: Vec<&OsStr> = command.get_args().collect();
+        assert_eq!(vec!["-l", "/tmp/a b.txt"], args);
+    }
+
+    #[test]
+    fn get_command_unbalanced_quote() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let mut command = CommandAction::new("echo 'unbalanced".to_string(), true).unwrap();
+
+        assert!(command.validate().is_err());
+        assert!(command.get_command(&change).is_err());
+
+        command.set_shell(Shell::Default);
+        assert!(command.validate().is_ok());
+    }
+
+    #[test]
+    fn get_command_with_shell() {
+        let change = Change::new(
+            EventKind::Any,
+            vec![PathBuf::from("/tmp/it's here")],
+            PathBuf::from("/"),
+        );
+        let mut command = CommandAction::new("cat {:p} | wc -l && date".to_string(), true).unwrap();
+        command.set_shell(Shell::Program("bash".to_string()));
+
+        let command = command.get_command(&change).unwrap();
+        assert_eq!("bash", command.get_program());
+        let args: Vec<&OsStr> = command.get_args().collect();
+        assert_eq!(
+            vec!["-c", r#"cat '/tmp/it'\''s here' | wc -l && date"#],
+            args
+        );
+    }
+
+    #[test]
+    fn get_command_with_shell_and_quoted_placeholder() {
+        let change = Change::new(
+            EventKind::Any,
+            vec![PathBuf::from("/tmp/a b.txt")],
+            PathBuf::from("/"),
+        );
+        let mut command = CommandAction::new(r#"wc -l "{:p}""#.to_string(), true).unwrap();
+        command.set_shell(Shell::Program("bash".to_string()));
+
+        let command = command.get_command(&change).unwrap();
+        let args: Vec<&OsStr> = command.get_args().collect();
+        assert_eq!(vec!["-c", r#"wc -l "/tmp/a b.txt""#], args);
+    }
+
+    #[test]
+    fn get_command_with_env() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let mut command = CommandAction::new("make".to_string(), true).unwrap();
+        let env = BTreeMap::from([("BUILD_DIR".to_string(), "/tmp/build".to_string())]);
+        command.set_env(env);
+
+        let command = command.get_command(&change).unwrap();
+        let envs: Vec<(&OsStr, Option<&OsStr>)> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("BUILD_DIR"), Some(OsStr::new("/tmp/build")))));
+        assert!(envs.contains(&(OsStr::new("EAGLE_PATH"), Some(OsStr::new("/")))));
+    }
+
+    #[test]
+    fn get_command_with_change_env() {
+        let event_kind = EventKind::Create(event::CreateKind::File);
+        let paths = vec![
+            PathBuf::from("/tmp/src/a.rs"),
+            PathBuf::from("/tmp/src/b.rs"),
+        ];
+        let change = Change::new(event_kind, paths, PathBuf::from("/tmp/src"));
+        let mut command = CommandAction::new("make".to_string(), true).unwrap();
+        command.set_env_clear(true);
+        command.set_cwd(PathBuf::from("/tmp"));
+
+        let command = command.get_command(&change).unwrap();
+        assert_eq!(Some(Path::new("/tmp")), command.get_current_dir());
+        let envs: Vec<(&OsStr, Option<&OsStr>)> = command.get_envs().collect();
+        assert_eq!(
+            vec![
+                (
+                    OsStr::new("EAGLE_CHANGED_PATHS"),
+                    Some(OsStr::new("/tmp/src/a.rs\n/tmp/src/b.rs"))
+                ),
+                (OsStr::new("EAGLE_EVENT_KIND"), Some(OsStr::new("create"))),
+                (OsStr::new("EAGLE_NEW_PATH"), Some(OsStr::new(""))),
+                (OsStr::new("EAGLE_OLD_PATH"), Some(OsStr::new(""))),
+                (OsStr::new("EAGLE_PATH"), Some(OsStr::new("/tmp/src/a.rs"))),
+                (OsStr::new("EAGLE_WATCH_ROOT"), Some(OsStr::new("/tmp/src"))),
+            ],
+            envs
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn handle_change_in_cwd_with_env() {
+        let change = Change::new(
+            EventKind::Any,
+            vec![PathBuf::from("/tmp/a.txt")],
+            PathBuf::from("/tmp"),
+        );
+        let mut command = CommandAction::new(
+            r#"test "$(pwd)" = / -a "$EAGLE_PATH" = /tmp/a.txt -a "$GREETING" = hi"#.to_string(),
+            true,
+        )
+        .unwrap();
+        command.set_shell(Shell::Default);
+        command.set_cwd(PathBuf::from("/"));
+        command.set_env(BTreeMap::from([("GREETING".to_string(), "hi".to_string())]));
+
+        assert!(command.handle_change(&change).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn handle_change_with_default_shell() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let mut command = CommandAction::new("true && test -d {:p}".to_string(), true).unwrap();
+        command.set_shell(Shell::Default);
+
+        assert!(command.handle_change(&change).is_ok());
+    }
+
+    #[test]
+    fn get_command_all_paths_as_arguments() {
+        let paths = vec![PathBuf::from("/tmp/a b"), PathBuf::from("/tmp/c")];
+        let change = Change::new(EventKind::Any, paths, PathBuf::from("/tmp"));
+        let command = CommandAction::new("touch {:paths} --{:kind}".to_string(), true).unwrap();
+
+        let command = command.get_command(&change).unwrap();
+        let args: Vec<&OsStr> = command.get_args().collect();
+        assert_eq!(vec!["/tmp/a b", "/tmp/c", "--any"], args);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancel_running_command() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let command = CommandAction::new("sleep 30".to_string(), true).unwrap();
+
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+
+        let started = Instant::now();
+        let mut execution = Execution::new(cancel);
+        assert!(command.execute(&change, &mut execution).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout_stops_sleeping_command() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let mut command = CommandAction::new("sleep 30".to_string(), true).unwrap();
+        command.set_timeout(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let result = command.handle_change(&change);
+        assert!(matches!(result, Err(Error::Timeout { .. })));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout_kills_command_ignoring_sigterm() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let mut command =
+            CommandAction::new(r#"sh -c 'trap "" TERM; sleep 30'"#.to_string(), true).unwrap();
+        command.set_timeout(Duration::from_millis(200));
+        command.set_grace_period(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let result = command.handle_change(&change);
+        assert!(matches!(result, Err(Error::Timeout { .. })));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn no_timeout_for_quick_command() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let mut command = CommandAction::new("date".to_string(), true).unwrap();
+        command.set_timeout(Duration::from_secs(10));
+
+        assert!(command.handle_change(&change).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn handle_change_failing_command() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let command = CommandAction::new("sh -c 'exit 3'".to_string(), true).unwrap();
+
+        match command.handle_change(&change) {
+            Err(Error::ActionExitStatus { status, .. }) => assert_eq!(Some(3), status.code()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_captures_output() {
+        let change = Change::new(EventKind::Any, vec![PathBuf::from("/")], PathBuf::from("/"));
+        let command =
+            CommandAction::new("sh -c 'echo out; echo err >&2; exit 2'".to_string(), true).unwrap();
+
+        let mut execution = Execution::default();
+        assert!(command.execute(&change, &mut execution).is_err());
+        assert_eq!(
+            Some(2),
+            execution.exit_status.and_then(|status| status.code())
+        );
+        assert_eq!("out\n", execution.stdout);
+        assert_eq!("err\n", execution.stderr);
+    }
 }
@@ -0,0 +1,112 @@
+use crate::actions::{self, Action, Change};
+use crate::error::{Error, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+/// Appends a line for every changed path to a log file. Each line starts
+/// with the time of the change in seconds since the Unix epoch.
+pub struct LogAction {
+    log_path: PathBuf,
+}
+
+impl LogAction {
+    pub fn new(log_path: PathBuf) -> LogAction {
+        LogAction { log_path }
+    }
+}
+
+impl Action for LogAction {
+    fn handle_change(&self, change: &Change) -> Result<()> {
+        if change.paths.is_empty() {
+            return Err(Error::NoPaths);
+        }
+
+        let timestamp = change
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let message = actions::event_kind_to_str(&change.kind);
+
+        let io_error = |source| Error::ActionIo {
+            path: self.log_path.clone(),
+            source,
+        };
+
+        let mut log_file = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)
+        {
+            Ok(log_file) => log_file,
+            Err(error) => {
+                println!("Could not open log file: {:?}", self.log_path);
+                return Err(io_error(error));
+            }
+        };
+
+        for path in change.paths.iter() {
+            if let Err(error) = writeln!(log_file, "{} {} on path {:?}", timestamp, message, path) {
+                println!("Could not write to log file: {:?}", self.log_path);
+                return Err(io_error(error));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("log to {:?}", self.log_path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use super::*;
+
+    use self::rand::distributions::Alphanumeric;
+    use self::rand::{thread_rng, Rng};
+    use crate::actions::{Action, Change};
+    use notify::{event, EventKind};
+    use std::env::temp_dir;
+    use std::fs::{read_to_string, remove_file};
+
+    #[test]
+    fn handle_change_appends_lines() {
+        let rand_part: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        let log_path = temp_dir().join("eagleeye-test-".to_string() + &rand_part);
+
+        let event_kind = EventKind::Modify(event::ModifyKind::Any);
+        let paths = vec![PathBuf::from("/a"), PathBuf::from("/b")];
+        let change = Change::new(event_kind, paths, PathBuf::from("/"));
+
+        let log = LogAction::new(log_path.clone());
+        assert!(log.handle_change(&change).is_ok());
+        assert!(log.handle_change(&change).is_ok());
+
+        let content = read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(4, lines.len());
+        assert!(lines[0].ends_with(" File or directory modified on path \"/a\""));
+        assert!(lines[3].ends_with(" File or directory modified on path \"/b\""));
+
+        remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn handle_change_unwritable_log_file() {
+        let event_kind = EventKind::Modify(event::ModifyKind::Any);
+        let change = Change::new(event_kind, vec![PathBuf::from("/a")], PathBuf::from("/"));
+
+        let log = LogAction::new(PathBuf::from("/directory/does/not/exist.log"));
+        assert!(log.handle_change(&change).is_err());
+    }
+}
@@ -1,8 +1,194 @@
 pub mod command;
+pub mod log;
 pub mod print;
+pub(crate) mod process;
+pub mod restart;
+pub mod template;
 
-use notify::Event;
+use crate::error::Result;
+use notify::event::{ModifyKind, RenameMode};
+use notify::EventKind;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
-pub trait Action {
-    fn handle_change(&self, event: &Event) -> Result<(), &'static str>;
+/// Something that is done when a watched path changes. Actions can be run
+/// on worker threads, so they must be `Send` and `Sync`.
+pub trait Action: Send + Sync {
+    fn handle_change(&self, change: &Change) -> Result<()>;
+
+    /// Called once when the action is added to a watch, before any change
+    /// happened. Actions that keep a process running start it here.
+    fn start(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the action is removed from its watch or replaced by other
+    /// actions. Actions that keep a process running stop it here, and ignore
+    /// changes that were already queued for them until they are started
+    /// again.
+    fn stop(&self) {}
+
+    /// Handles a change like `handle_change`, giving up early once
+    /// `execution.cancel` is cancelled and reporting details like the exit
+    /// status of a process in `execution`. Actions that finish quickly and
+    /// have nothing to report don't need to implement this, and the default
+    /// implementation just calls `handle_change`.
+    fn execute(&self, change: &Change, _execution: &mut Execution) -> Result<()> {
+        self.handle_change(change)
+    }
+
+    /// Returns a short description of the action for reports, like
+    /// `command "make"`.
+    fn describe(&self) -> String {
+        "action".to_string()
+    }
+}
+
+/// The maximum number of bytes of a process's stdout and stderr that are
+/// kept in an `Execution`. When a process writes more, the end is kept.
+pub const OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// What an action is run with, and what it reports back besides success or
+/// failure.
+#[derive(Debug, Default)]
+pub struct Execution {
+    /// Cancelled when the action should stop early.
+    pub cancel: CancelToken,
+    /// The exit status of the process the action ran, if it ran one.
+    pub exit_status: Option<ExitStatus>,
+    /// The end of what the process wrote to stdout, at most `OUTPUT_LIMIT`
+    /// bytes.
+    pub stdout: String,
+    /// The end of what the process wrote to stderr, at most `OUTPUT_LIMIT`
+    /// bytes.
+    pub stderr: String,
+}
+
+impl Execution {
+    pub fn new(cancel: CancelToken) -> Execution {
+        Execution {
+            cancel,
+            ..Default::default()
+        }
+    }
+}
+
+/// Tells a running action that it should stop. Clones share the same state,
+/// so cancelling one cancels all of them.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A change to one or more paths of a watch, as handed to its actions.
+#[derive(Clone, Debug)]
+pub struct Change {
+    /// The kind of the change. If the paths changed in different ways, this
+    /// is `EventKind::Any`.
+    pub kind: EventKind,
+    pub paths: Vec<PathBuf>,
+    /// The old and new paths of renamed files or directories, for renames
+    /// of which both paths were seen. Both paths are also in `paths`.
+    pub renames: Vec<(PathBuf, PathBuf)>,
+    /// The watched file or directory the first changed path was found
+    /// under.
+    pub watch_root: PathBuf,
+    pub time: SystemTime,
+}
+
+impl Change {
+    pub fn new(kind: EventKind, paths: Vec<PathBuf>, watch_root: PathBuf) -> Change {
+        Change {
+            kind,
+            paths,
+            renames: vec![],
+            watch_root,
+            time: SystemTime::now(),
+        }
+    }
+
+    /// Adds the paths of a later change to this one. The kind becomes the
+    /// one returned by `merge_kinds`.
+    pub fn merge(&mut self, other: Change) {
+        self.kind = merge_kinds(self.kind, other.kind);
+        for path in other.paths {
+            if !self.paths.contains(&path) {
+                self.paths.push(path);
+            }
+        }
+        for rename in other.renames {
+            if !self.renames.contains(&rename) {
+                self.renames.push(rename);
+            }
+        }
+        self.time = other.time;
+    }
+
+    /// Returns the old and new path of the first rename, if there is one.
+    pub fn first_rename(&self) -> Option<(&Path, &Path)> {
+        self.renames
+            .first()
+            .map(|(old, new)| (old.as_path(), new.as_path()))
+    }
+}
+
+/// Returns the kind of a change that is made up of changes of two kinds. That
+/// is the kind itself if both are the same, a rename if both are parts of
+/// renames, and `EventKind::Any` otherwise.
+pub fn merge_kinds(kind: EventKind, other: EventKind) -> EventKind {
+    match (kind, other) {
+        (kind, other) if kind == other => kind,
+        (EventKind::Modify(ModifyKind::Name(_)), EventKind::Modify(ModifyKind::Name(_))) => {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+        }
+        _ => EventKind::Any,
+    }
+}
+
+/// The kind of the change that actions run with when they run at startup,
+/// before anything changed. File events of this kind are ignored, so it
+/// only ever stands for such a change.
+pub const START_KIND: EventKind = EventKind::Other;
+
+/// Returns a human readable description of an event kind.
+pub fn event_kind_to_str(kind: &EventKind) -> &'static str {
+    match *kind {
+        EventKind::Access(_) => "File or directory accessed",
+        EventKind::Create(_) => "File or directory created",
+        EventKind::Modify(ModifyKind::Name(_)) => "File or directory renamed",
+        EventKind::Modify(_) => "File or directory modified",
+        EventKind::Remove(_) => "File or directory removed",
+        EventKind::Other => "Initial run",
+        _ => "Unknown change",
+    }
+}
+
+/// Returns a short, lowercase name for an event kind, for use in commands.
+pub fn event_kind_name(kind: &EventKind) -> &'static str {
+    match *kind {
+        EventKind::Access(_) => "access",
+        EventKind::Create(_) => "create",
+        EventKind::Modify(ModifyKind::Name(_)) => "rename",
+        EventKind::Modify(_) => "modify",
+        EventKind::Remove(_) => "remove",
+        EventKind::Any => "any",
+        EventKind::Other => "start",
+    }
 }
@@ -1,5 +1,7 @@
-use crate::actions::Action;
-use notify::{Event, EventKind};
+use crate::actions::{self, Action, Change};
+use crate::error::{Error, Result};
+use notify::EventKind;
+use std::path::PathBuf;
 
 pub struct PrintAction;
 
@@ -9,13 +11,7 @@ impl PrintAction {
     }
 
     pub fn event_kind_to_str(&self, kind: &EventKind) -> &'static str {
-        match *kind {
-            EventKind::Access(_) => "File or directory accessed",
-            EventKind::Create(_) => "File or directory created",
-            EventKind::Modify(_) => "File or directory modified",
-            EventKind::Remove(_) => "File or directory removed",
-            _ => "Unknown change",
-        }
+        actions::event_kind_to_str(kind)
     }
 }
 
@@ -26,19 +22,32 @@ impl Default for PrintAction {
 }
 
 impl Action for PrintAction {
-    fn handle_change(&self, event: &Event) -> Result<(), &'static str> {
-        if event.paths.is_empty() {
+    fn handle_change(&self, change: &Change) -> Result<()> {
+        if change.paths.is_empty() {
             println!("No path for event");
-            return Err("No path for event");
+            return Err(Error::NoPaths);
         }
 
-        for path in event.paths.iter() {
-            let message = self.event_kind_to_str(&event.kind);
+        for (old, new) in change.renames.iter() {
+            println!("File or directory renamed {:?} -> {:?}", old, new);
+        }
+        let is_renamed = |path: &PathBuf| {
+            change
+                .renames
+                .iter()
+                .any(|(old, new)| old == path || new == path)
+        };
+        for path in change.paths.iter().filter(|path| !is_renamed(path)) {
+            let message = self.event_kind_to_str(&change.kind);
             println!("{} on path {:?}", message, path);
         }
 
         Ok(())
     }
+
+    fn describe(&self) -> String {
+        "print".to_string()
+    }
 }
 
 #[cfg(test)]
@@ -47,8 +56,8 @@ mod test {
 
     use super::*;
 
-    use crate::actions::Action;
-    use notify::{event, Event, EventKind};
+    use crate::actions::{Action, Change};
+    use notify::{event, EventKind};
     use std::path::PathBuf;
 
     #[test]
@@ -61,10 +70,10 @@ mod test {
     fn handle_change() {
         let event_kind = EventKind::Modify(event::ModifyKind::Any);
         let path_buf = PathBuf::from("/");
-        let event = Event::new(event_kind).add_path(path_buf);
+        let change = Change::new(event_kind, vec![path_buf], PathBuf::from("/"));
 
         let print = PrintAction::new();
-        let result = print.handle_change(&event);
+        let result = print.handle_change(&change);
 
         // We can't capture the output, so just make sure the function
         // returns Ok.
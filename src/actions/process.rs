@@ -0,0 +1,156 @@
+use std::io::{self, Read, Write};
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+
+/// Starts a command in a new process group, so it can be stopped together
+/// with any processes it started. The signals eagle blocks for itself are
+/// unblocked again in the child.
+pub fn spawn_in_group(command: &mut Command) -> io::Result<Child> {
+    #[cfg(unix)]
+    unsafe {
+        command
+            .process_group(0)
+            .pre_exec(crate::signals::unblock_exit_signals);
+    }
+
+    command.spawn()
+}
+
+/// Sends a signal to the process group of a child started with
+/// `spawn_in_group`. Does nothing on platforms without signals.
+#[cfg(unix)]
+pub fn send_signal(child: &Child, signal: i32) {
+    unsafe {
+        libc::kill(-(child.id() as i32), signal);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn send_signal(_child: &Child, _signal: i32) {}
+
+/// Kills the process group of a child started with `spawn_in_group`. On
+/// platforms without process groups, only the child itself is killed.
+#[cfg(unix)]
+pub fn kill_group(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as i32), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kill_group(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Returns the signal that politely asks a process to exit, SIGTERM on Unix.
+#[cfg(unix)]
+pub fn terminate_signal() -> i32 {
+    libc::SIGTERM
+}
+
+#[cfg(not(unix))]
+pub fn terminate_signal() -> i32 {
+    0
+}
+
+/// Stops a child started with `spawn_in_group` and waits for it. The
+/// process group is sent `signal` first, and killed if the child hasn't
+/// exited after `grace_period`. Returns true if it had to be killed.
+pub fn stop(child: &mut Child, signal: i32, grace_period: Duration) -> bool {
+    if let Ok(Some(_)) = child.try_wait() {
+        return false;
+    }
+
+    send_signal(child, signal);
+
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline {
+        match child.try_wait() {
+            Ok(Some(_)) | Err(_) => return false,
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+
+    kill_group(child);
+    let _ = child.wait();
+
+    true
+}
+
+/// Keeps the last bytes read from a pipe of a child, on a thread of its
+/// own, optionally passing everything on to another writer like stdout.
+pub struct OutputCapture {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    reader: JoinHandle<()>,
+}
+
+impl OutputCapture {
+    /// Starts reading from `pipe` until it's closed, keeping at most the
+    /// last `limit` bytes.
+    pub fn start<R>(mut pipe: R, mut echo: Option<Box<dyn Write + Send>>, limit: usize) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        let buffer = Arc::new(Mutex::new(vec![]));
+        let captured = buffer.clone();
+
+        let reader = thread::spawn(move || {
+            let mut chunk = [0; 8192];
+            loop {
+                let length = match pipe.read(&mut chunk) {
+                    Ok(0) | Err(_) => break,
+                    Ok(length) => length,
+                };
+
+                if let Some(echo) = echo.as_mut() {
+                    let _ = echo.write_all(&chunk[..length]);
+                    let _ = echo.flush();
+                }
+
+                let mut captured = captured.lock().unwrap();
+                captured.extend_from_slice(&chunk[..length]);
+                if captured.len() > limit {
+                    let excess = captured.len() - limit;
+                    captured.drain(..excess);
+                }
+            }
+        });
+
+        OutputCapture { buffer, reader }
+    }
+
+    /// Waits for the pipe to be closed and returns what was kept. Processes
+    /// started in the background by the child can keep the pipe open, so
+    /// this gives up waiting after `timeout`.
+    pub fn finish(self, timeout: Duration) -> String {
+        let deadline = Instant::now() + timeout;
+        while !self.reader.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let buffer = self.buffer.lock().unwrap();
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture_keeps_the_end() {
+        let capture = OutputCapture::start(&b"0123456789"[..], None, 4);
+        assert_eq!("6789", capture.finish(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn capture_everything_below_the_limit() {
+        let capture = OutputCapture::start(&b"done\n"[..], None, 1024);
+        assert_eq!("done\n", capture.finish(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::actions::command::CommandAction;
+use crate::actions::process;
+use crate::actions::{Action, Change};
+use crate::error::{Error, Result};
+use notify::EventKind;
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// How often the process is checked for having exited on its own.
+const MONITOR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keeps a long-running process, like a development server, running and
+/// restarts it whenever a change happens.
+///
+/// On a change, the process is sent a signal (SIGTERM by default) and given
+/// a grace period to exit. If it's still running after that, its whole
+/// process group is killed. Then a fresh instance is started. If the process
+/// exits on its own between changes, this is reported.
+pub struct RestartAction {
+    command: CommandAction,
+    quiet: bool,
+    signal: i32,
+    grace_period: Duration,
+    child: Arc<Mutex<Option<Child>>>,
+    /// Set by `stop`, so changes that were queued before don't start the
+    /// process again.
+    stopped: AtomicBool,
+}
+
+impl RestartAction {
+    pub fn new(command: CommandAction, quiet: bool) -> RestartAction {
+        RestartAction {
+            command,
+            quiet,
+            signal: process::terminate_signal(),
+            grace_period: Duration::from_secs(5),
+            child: Arc::new(Mutex::new(None)),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Sets the signal that asks the process to exit, like `libc::SIGINT`.
+    /// Signals are only used on Unix. On other platforms, the process is
+    /// always killed.
+    pub fn set_signal(&mut self, signal: i32) {
+        self.signal = signal;
+    }
+
+    /// Sets how long to wait for the process to exit after sending the
+    /// signal, before killing it.
+    pub fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Returns the process id of the running process, if there is one.
+    pub fn pid(&self) -> Option<u32> {
+        self.child.lock().unwrap().as_ref().map(|child| child.id())
+    }
+
+    fn spawn(&self, change: &Change) -> Result<Child> {
+        let mut command = self.command.get_command(change)?;
+        // The process runs in its own process group, which would be stopped
+        // if it tried to read from the terminal.
+        command.stdin(Stdio::null());
+        if self.quiet {
+            command.stdout(Stdio::null()).stderr(Stdio::null());
+        }
+
+        match process::spawn_in_group(&mut command) {
+            Ok(child) => Ok(child),
+            Err(source) => {
+                println!("Could not start process: {:?}", command);
+                Err(Error::ActionSpawn {
+                    command: self.command.command_line().to_string(),
+                    source,
+                })
+            }
+        }
+    }
+
+    fn stop_child(&self, mut child: Child) {
+        if process::stop(&mut child, self.signal, self.grace_period) {
+            println!(
+                "Process {} did not exit within {:?}, killed it.",
+                child.id(),
+                self.grace_period
+            );
+        }
+    }
+}
+
+impl Action for RestartAction {
+    fn handle_change(&self, change: &Change) -> Result<()> {
+        let mut running = self.child.lock().unwrap();
+        if self.stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if let Some(child) = running.take() {
+            self.stop_child(child);
+        }
+
+        *running = Some(self.spawn(change)?);
+
+        Ok(())
+    }
+
+    /// Starts the process for the first time, before any change happened,
+    /// and starts watching for it to exit unexpectedly.
+    fn start(&self) -> Result<()> {
+        let change = Change::new(EventKind::Any, vec![], PathBuf::new());
+        let mut running = self.child.lock().unwrap();
+        self.stopped.store(false, Ordering::SeqCst);
+        *running = Some(self.spawn(&change)?);
+        drop(running);
+
+        let monitored = Arc::downgrade(&self.child);
+        thread::spawn(move || monitor(monitored));
+
+        Ok(())
+    }
+
+    /// Stops the running process, if there is one.
+    fn stop(&self) {
+        let mut running = self.child.lock().unwrap();
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(child) = running.take() {
+            self.stop_child(child);
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("restart {:?}", self.command.command_line())
+    }
+}
+
+impl Drop for RestartAction {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Reports when the process exits while it isn't being restarted. The
+/// restart holds the lock, so exits caused by it are never seen here.
+fn monitor(child: Weak<Mutex<Option<Child>>>) {
+    while let Some(child) = child.upgrade() {
+        {
+            let mut running = child.lock().unwrap();
+            if let Some(process) = running.as_mut() {
+                if let Ok(Some(status)) = process.try_wait() {
+                    println!("Process {} exited unexpectedly: {}", process.id(), status);
+                    *running = None;
+                }
+            }
+        }
+
+        drop(child);
+        thread::sleep(MONITOR_INTERVAL);
+    }
+}
+
+/// Returns the number of a signal given by its name, like "SIGTERM" or
+/// "TERM", or `None` if the name isn't known.
+#[cfg(unix)]
+pub fn parse_signal(name: &str) -> Option<i32> {
+    let name = name.to_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+
+    match name {
+        "HUP" => Some(libc::SIGHUP),
+        "INT" => Some(libc::SIGINT),
+        "QUIT" => Some(libc::SIGQUIT),
+        "KILL" => Some(libc::SIGKILL),
+        "USR1" => Some(libc::SIGUSR1),
+        "USR2" => Some(libc::SIGUSR2),
+        "TERM" => Some(libc::SIGTERM),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn parse_signal(_name: &str) -> Option<i32> {
+    Some(0)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    use crate::actions::Action;
+    use notify::{event, EventKind};
+    use std::time::Instant;
+
+    fn restart_action(command_line: &str) -> RestartAction {
+        let command = CommandAction::new(command_line.to_string(), true).unwrap();
+        RestartAction::new(command, true)
+    }
+
+    fn is_running(pid: u32) -> bool {
+        unsafe { libc::kill(pid as i32, 0) == 0 }
+    }
+
+    #[test]
+    fn parse_signal_names() {
+        assert_eq!(Some(libc::SIGTERM), parse_signal("SIGTERM"));
+        assert_eq!(Some(libc::SIGINT), parse_signal("int"));
+        assert_eq!(Some(libc::SIGHUP), parse_signal("SigHup"));
+        assert_eq!(None, parse_signal("SIGFOO"));
+    }
+
+    #[test]
+    fn restart_on_change() {
+        let restart = restart_action("sleep 30");
+        restart.start().unwrap();
+        let first_pid = restart.pid().unwrap();
+        assert!(is_running(first_pid));
+
+        let event_kind = EventKind::Modify(event::ModifyKind::Any);
+        let change = Change::new(event_kind, vec![PathBuf::from("/")], PathBuf::from("/"));
+        restart.handle_change(&change).unwrap();
+
+        let second_pid = restart.pid().unwrap();
+        assert_ne!(first_pid, second_pid);
+        assert!(!is_running(first_pid));
+        assert!(is_running(second_pid));
+
+        restart.stop();
+        assert!(!is_running(second_pid));
+    }
+
+    #[test]
+    fn kill_after_grace_period() {
+        let mut restart = restart_action(r#"sh -c 'trap "" TERM; sleep 30'"#);
+        restart.set_grace_period(Duration::from_millis(100));
+        restart.start().unwrap();
+        let pid = restart.pid().unwrap();
+
+        // Give the shell time to set up the trap before signalling it.
+        thread::sleep(Duration::from_millis(200));
+        let started = Instant::now();
+        restart.stop();
+
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(!is_running(pid));
+    }
+
+    #[test]
+    fn report_unexpected_exit() {
+        let restart = restart_action("true");
+        restart.start().unwrap();
+
+        thread::sleep(MONITOR_INTERVAL * 3);
+        assert_eq!(None, restart.pid());
+    }
+}
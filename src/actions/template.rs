@@ -0,0 +1,385 @@
+use crate::actions::{self, Change};
+use crate::files_watcher::relative_path;
+use std::borrow::Cow;
+use std::mem;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// A value from a change that can be substituted into a template.
+/// Placeholders for a single path use the first changed path, and the ones
+/// for renames the first rename.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Placeholder {
+    Path,
+    Name,
+    Stem,
+    Extension,
+    Directory,
+    Relative,
+    Kind,
+    Time,
+    Paths,
+    Root,
+    OldPath,
+    NewPath,
+}
+
+impl Placeholder {
+    fn from_name(name: &str) -> Option<Placeholder> {
+        match name {
+            "p" => Some(Placeholder::Path),
+            "name" => Some(Placeholder::Name),
+            "stem" => Some(Placeholder::Stem),
+            "ext" => Some(Placeholder::Extension),
+            "dir" => Some(Placeholder::Directory),
+            "rel" => Some(Placeholder::Relative),
+            "kind" => Some(Placeholder::Kind),
+            "time" => Some(Placeholder::Time),
+            "paths" => Some(Placeholder::Paths),
+            "root" => Some(Placeholder::Root),
+            "old" => Some(Placeholder::OldPath),
+            "new" => Some(Placeholder::NewPath),
+            _ => None,
+        }
+    }
+}
+
+/// The quotes of a POSIX shell that a placeholder in a command line is
+/// written inside of.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quotes {
+    None,
+    Single,
+    Double,
+}
+
+impl Quotes {
+    /// Returns the quotes that are open after `text`, which follows text
+    /// with these quotes open.
+    fn after(self, text: &str) -> Quotes {
+        let mut quotes = self;
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            quotes = match (quotes, c) {
+                (Quotes::None, '\\') | (Quotes::Double, '\\') => {
+                    chars.next();
+                    quotes
+                }
+                (Quotes::None, '\'') => Quotes::Single,
+                (Quotes::None, '"') => Quotes::Double,
+                (Quotes::Single, '\'') | (Quotes::Double, '"') => Quotes::None,
+                _ => quotes,
+            };
+        }
+
+        quotes
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// A string with placeholders like `{:p}` that are replaced with details
+/// of a change. `{{` and `}}` stand for a literal `{` and `}`. A `{` that
+/// isn't followed by `:` or another `{` is kept as it is, so `{}` needs no
+/// escaping.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses a template, failing on unknown or unclosed placeholders.
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    text.push(c);
+                }
+                ('{', Some(':')) => {
+                    chars.next();
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed placeholder \"{{:{}\"", name)),
+                        }
+                    }
+
+                    let placeholder = match Placeholder::from_name(&name) {
+                        Some(placeholder) => placeholder,
+                        None => return Err(format!("unknown placeholder \"{{:{}}}\"", name)),
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(mem::take(&mut text)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                }
+                _ => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// Returns true if the template consists of nothing but `{:paths}`.
+    pub fn is_all_paths(&self) -> bool {
+        self.parts == [Part::Placeholder(Placeholder::Paths)]
+    }
+
+    /// Replaces the placeholders with the details of a change. Every
+    /// replacement is passed through `quote` first, together with the
+    /// quotes the placeholder is written inside of.
+    pub fn render(&self, change: &Change, quote: fn(&str, Quotes) -> Cow<'_, str>) -> String {
+        let mut rendered = String::new();
+        let mut quotes = Quotes::None;
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => {
+                    rendered.push_str(text);
+                    quotes = quotes.after(text);
+                }
+                Part::Placeholder(Placeholder::Paths) => {
+                    let paths: Vec<String> = change
+                        .paths
+                        .iter()
+                        .map(|path| quote(&path.to_string_lossy(), quotes).into_owned())
+                        .collect();
+                    rendered.push_str(&paths.join(" "));
+                }
+                Part::Placeholder(placeholder) => {
+                    rendered.push_str(&quote(&value(*placeholder, change), quotes));
+                }
+            }
+        }
+
+        rendered
+    }
+}
+
+/// Leaves a replacement as it is, for use with `Template::render`.
+pub fn no_quoting(s: &str, _quotes: Quotes) -> Cow<'_, str> {
+    Cow::Borrowed(s)
+}
+
+/// Quotes a replacement for a POSIX shell, for use with `Template::render`.
+/// Outside of quotes, it's quoted to stay a single word. Inside of quotes
+/// written around the placeholder, only the characters that would end the
+/// quotes or be expanded are escaped.
+pub fn posix_quoting(s: &str, quotes: Quotes) -> Cow<'_, str> {
+    match quotes {
+        Quotes::None => shell_words::quote(s),
+        Quotes::Single if s.contains('\'') => Cow::Owned(s.replace('\'', r"'\''")),
+        Quotes::Double if s.contains(['\\', '"', '$', '`']) => {
+            let mut quoted = String::with_capacity(s.len());
+            for c in s.chars() {
+                if matches!(c, '\\' | '"' | '$' | '`') {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            Cow::Owned(quoted)
+        }
+        Quotes::Single | Quotes::Double => Cow::Borrowed(s),
+    }
+}
+
+/// Quotes a replacement for `cmd`, for use with `Template::render`. `cmd`
+/// only knows double quotes, so a replacement that isn't inside of them
+/// already is put in double quotes if it contains spaces or special
+/// characters.
+pub fn cmd_quoting(s: &str, quotes: Quotes) -> Cow<'_, str> {
+    let needs_quotes =
+        s.is_empty() || s.contains(|c: char| c.is_whitespace() || "&|<>^()".contains(c));
+    if quotes != Quotes::Double && needs_quotes {
+        Cow::Owned(format!("\"{}\"", s))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn value(placeholder: Placeholder, change: &Change) -> String {
+    let path = change
+        .paths
+        .first()
+        .map(|path| path.as_path())
+        .unwrap_or(Path::new(""));
+
+    let value = match placeholder {
+        Placeholder::Path => Some(path.as_os_str()),
+        Placeholder::Name => path.file_name(),
+        Placeholder::Stem => path.file_stem(),
+        Placeholder::Extension => path.extension(),
+        Placeholder::Directory => path.parent().map(|parent| parent.as_os_str()),
+        Placeholder::Relative => {
+            return relative_path(&change.watch_root, path)
+                .to_string_lossy()
+                .into_owned()
+        }
+        Placeholder::Kind => return actions::event_kind_name(&change.kind).to_string(),
+        Placeholder::Time => {
+            let seconds = change
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+            return seconds.to_string();
+        }
+        Placeholder::Paths => None,
+        Placeholder::Root => Some(change.watch_root.as_os_str()),
+        Placeholder::OldPath => change.first_rename().map(|(old, _)| old.as_os_str()),
+        Placeholder::NewPath => change.first_rename().map(|(_, new)| new.as_os_str()),
+    };
+
+    value
+        .map(|value| value.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use notify::{event, EventKind};
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn change() -> Change {
+        let mut change = Change::new(
+            EventKind::Create(event::CreateKind::File),
+            vec![
+                PathBuf::from("/project/src/lib/util.rs"),
+                PathBuf::from("/project/src/main.rs"),
+            ],
+            PathBuf::from("/project"),
+        );
+        change.time = UNIX_EPOCH + Duration::from_secs(1700000000);
+        change
+    }
+
+    fn render(template: &str) -> String {
+        Template::parse(template)
+            .unwrap()
+            .render(&change(), no_quoting)
+    }
+
+    #[test]
+    fn path_placeholders() {
+        assert_eq!("/project/src/lib/util.rs", render("{:p}"));
+        assert_eq!("util.rs", render("{:name}"));
+        assert_eq!("util", render("{:stem}"));
+        assert_eq!("rs", render("{:ext}"));
+        assert_eq!("/project/src/lib", render("{:dir}"));
+        assert_eq!("src/lib/util.rs", render("{:rel}"));
+        assert_eq!("/project", render("{:root}"));
+    }
+
+    #[test]
+    fn change_placeholders() {
+        assert_eq!("create at 1700000000", render("{:kind} at {:time}"));
+        assert_eq!(
+            "/project/src/lib/util.rs /project/src/main.rs",
+            render("{:paths}")
+        );
+    }
+
+    #[test]
+    fn rename_placeholders() {
+        assert_eq!(" -> ", render("{:old} -> {:new}"));
+
+        let mut change = change();
+        change.kind = EventKind::Modify(event::ModifyKind::Name(event::RenameMode::Both));
+        change.paths = vec![
+            PathBuf::from("/project/a.rs"),
+            PathBuf::from("/project/b.rs"),
+        ];
+        change.renames = vec![(change.paths[0].clone(), change.paths[1].clone())];
+
+        let template = Template::parse("{:kind}: {:old} -> {:new}").unwrap();
+        assert_eq!(
+            "rename: /project/a.rs -> /project/b.rs",
+            template.render(&change, no_quoting)
+        );
+    }
+
+    #[test]
+    fn text_around_placeholders() {
+        assert_eq!(
+            "cp util.rs util.rs.bak",
+            render("cp {:name} {:stem}.{:ext}.bak")
+        );
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!("{:p} {} }", render("{{:p}} {} }}"));
+        assert_eq!("awk '{print $1}'", render("awk '{print $1}'"));
+    }
+
+    #[test]
+    fn quoting() {
+        let mut change = change();
+        change.paths = vec![PathBuf::from("/tmp/a b"), PathBuf::from("/tmp/c")];
+
+        let template = Template::parse("ls {:paths} {:name}").unwrap();
+        assert_eq!(
+            "ls '/tmp/a b' /tmp/c 'a b'",
+            template.render(&change, posix_quoting)
+        );
+    }
+
+    #[test]
+    fn quoting_inside_quotes() {
+        let mut change = change();
+        change.paths = vec![PathBuf::from("/tmp/it's $HOME")];
+
+        let template = Template::parse(r#"echo "{:p}" '{:p}' {:p} "\"'{:p}""#).unwrap();
+        assert_eq!(
+            r#"echo "/tmp/it's \$HOME" '/tmp/it'\''s $HOME' '/tmp/it'\''s $HOME' "\"'/tmp/it's \$HOME""#,
+            template.render(&change, posix_quoting)
+        );
+    }
+
+    #[test]
+    fn cmd_quoting_outside_quotes() {
+        let mut change = change();
+        change.paths = vec![PathBuf::from(r"C:\my files\a.txt")];
+
+        let template = Template::parse(r#"type {:p} "{:p}" {:ext}"#).unwrap();
+        assert_eq!(
+            r#"type "C:\my files\a.txt" "C:\my files\a.txt" txt"#,
+            template.render(&change, cmd_quoting)
+        );
+    }
+
+    #[test]
+    fn unknown_placeholder() {
+        let error = Template::parse("echo {:path}").unwrap_err();
+        assert_eq!("unknown placeholder \"{:path}\"", error);
+    }
+
+    #[test]
+    fn unclosed_placeholder() {
+        let error = Template::parse("echo {:p").unwrap_err();
+        assert_eq!("unclosed placeholder \"{:p\"", error);
+    }
+
+    #[test]
+    fn is_all_paths() {
+        assert!(Template::parse("{:paths}").unwrap().is_all_paths());
+        assert!(!Template::parse("-{:paths}").unwrap().is_all_paths());
+    }
+}
@@ -1,44 +1,989 @@
 extern crate toml;
 
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use crate::actions::restart;
+use crate::actions::template::Template;
+use crate::error::{ConfigProblem, Error, Result};
+use crate::filter::{EventType, PathFilter, EVENT_TYPE_NAMES};
+use crate::interpolate;
+use crate::worker_pool::{FailurePolicy, QueuePolicy};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The names of the config files that are looked for in the working
+/// directory and its ancestors, in order of preference.
+const CONFIG_FILE_NAMES: [&str; 2] = [".eagle.toml", "eagle.toml"];
+
+/// The action types that can be used in a config file.
+const ACTION_TYPES: [&str; 4] = ["print", "command", "restart", "log"];
+
+/// Keys that are not known are collected in the `unknown` field of each
+/// table, so they can all be reported at once.
+type UnknownKeys = BTreeMap<String, toml::Value>;
+
+/// Looks up the value of a variable while interpolating settings.
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub settings: Option<SettingsConfig>,
     pub watchers: Option<Vec<WatcherSettings>>,
+    /// Environment variables that are set for the commands that are run.
+    pub env: Option<BTreeMap<String, String>>,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct SettingsConfig {
     pub quiet: Option<bool>,
+    pub debounce_ms: Option<u64>,
+    pub workers: Option<usize>,
+    pub shutdown_timeout_ms: Option<u64>,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct WatcherSettings {
+    pub action_type: Option<String>,
+    pub execute: Option<String>,
+    pub path: Option<String>,
+    pub paths: Option<Vec<String>>,
+    pub actions: Option<Vec<ActionSettings>>,
+    pub shell: Option<ShellSetting>,
+    pub recursive: Option<bool>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub respect_gitignore: Option<bool>,
+    pub debounce_ms: Option<u64>,
+    pub max_concurrent: Option<usize>,
+    pub on_busy: Option<String>,
+    pub on_failure: Option<String>,
+    pub run_on_start: Option<bool>,
+    pub events: Option<Vec<String>>,
+    /// The directory the commands of the watcher run in.
+    pub cwd: Option<String>,
+    /// Environment variables for the commands of the watcher, which
+    /// override the ones in the `[env]` table.
+    pub env: Option<BTreeMap<String, String>>,
+    /// Start the commands of the watcher without the environment variables
+    /// eagle was started with.
+    pub env_clear: Option<bool>,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct ActionSettings {
     pub action_type: String,
-    pub execute: String,
-    pub path: String,
+    pub execute: Option<String>,
+    pub file: Option<String>,
+    pub shell: Option<ShellSetting>,
+    pub signal: Option<String>,
+    pub grace_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    #[serde(flatten)]
+    pub unknown: UnknownKeys,
+}
+
+/// Whether a command is run with a shell. `shell = true` uses the platform's
+/// shell, while `shell = "bash"` names the shell program to use.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ShellSetting {
+    Enabled(bool),
+    Program(String),
+}
+
+impl WatcherSettings {
+    /// Returns the paths of the watcher, from both the `path` and the
+    /// `paths` settings.
+    pub fn all_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.path.iter().cloned().collect();
+        if let Some(more_paths) = &self.paths {
+            paths.extend(more_paths.iter().cloned());
+        }
+
+        paths
+    }
+
+    /// Returns the actions of the watcher in the order they run. An action
+    /// set directly on the watcher with `action_type` runs before the ones
+    /// in the `actions` list.
+    pub fn all_actions(&self) -> Vec<ActionSettings> {
+        let mut actions = vec![];
+        if let Some(action_type) = &self.action_type {
+            actions.push(ActionSettings {
+                action_type: action_type.to_owned(),
+                execute: self.execute.to_owned(),
+                ..Default::default()
+            });
+        }
+        if let Some(more_actions) = &self.actions {
+            actions.extend(more_actions.iter().cloned());
+        }
+
+        actions
+    }
+
+    /// Returns true if the watcher runs different actions than `other`,
+    /// including different settings for all of its commands, like their
+    /// shell or environment.
+    pub fn has_other_actions(&self, other: &WatcherSettings) -> bool {
+        self.all_actions() != other.all_actions()
+            || self.shell != other.shell
+            || self.cwd != other.cwd
+            || self.env != other.env
+            || self.env_clear != other.env_clear
+    }
 }
 
-pub fn parse(config_content: String) -> Option<Config> {
-    match toml::from_str(&config_content) {
-        Ok(config) => Some(config),
-        Err(_) => None,
+/// How a watcher of a reloaded config relates to the watchers of the config
+/// that was loaded before. Watchers are told apart by their paths, and the
+/// numbers are indices into the old and the new list of watchers.
+#[derive(Debug, PartialEq)]
+pub enum WatcherDiff {
+    /// A watcher with the same paths and the same settings.
+    Unchanged { old: usize, new: usize },
+    /// A watcher with the same paths but different settings.
+    Changed { old: usize, new: usize },
+    /// A watcher whose paths were not watched by an old watcher.
+    Added { new: usize },
+    /// An old watcher whose paths are not watched by a new watcher.
+    Removed { old: usize },
+}
+
+/// Compares the watchers of two configs. Removed watchers are listed first,
+/// followed by the new watchers in order.
+pub fn diff_watchers(old: &[WatcherSettings], new: &[WatcherSettings]) -> Vec<WatcherDiff> {
+    let sorted_paths = |watcher: &WatcherSettings| {
+        let mut paths = watcher.all_paths();
+        paths.sort();
+        paths
+    };
+    let old_paths: Vec<Vec<String>> = old.iter().map(sorted_paths).collect();
+
+    let mut matched = vec![false; old.len()];
+    let mut diffs = vec![];
+    for (new_index, watcher) in new.iter().enumerate() {
+        let paths = sorted_paths(watcher);
+        let old_index = (0..old.len()).find(|&index| !matched[index] && old_paths[index] == paths);
+        diffs.push(match old_index {
+            Some(old_index) => {
+                matched[old_index] = true;
+                if old[old_index] == *watcher {
+                    WatcherDiff::Unchanged {
+                        old: old_index,
+                        new: new_index,
+                    }
+                } else {
+                    WatcherDiff::Changed {
+                        old: old_index,
+                        new: new_index,
+                    }
+                }
+            }
+            None => WatcherDiff::Added { new: new_index },
+        });
     }
+
+    let removed = (0..old.len())
+        .filter(|&index| !matched[index])
+        .map(|old| WatcherDiff::Removed { old });
+
+    removed.chain(diffs).collect()
 }
 
-pub fn parse_file(path: &Path) -> Option<Config> {
-    if !path.exists() {
-        println!("ERROR: Config file not found: {}", path.display());
-        return None;
+impl Config {
+    /// Resolves relative watcher paths, working directories and log files
+    /// against `base_dir`, the directory of the config file.
+    pub fn resolve_paths(&mut self, base_dir: &Path) {
+        let resolve = |path: &mut String| {
+            *path = base_dir.join(&*path).to_string_lossy().into_owned();
+        };
+
+        for watcher in self.watchers.iter_mut().flatten() {
+            watcher.path.iter_mut().for_each(resolve);
+            watcher.paths.iter_mut().flatten().for_each(resolve);
+            watcher.cwd.iter_mut().for_each(resolve);
+            for action in watcher.actions.iter_mut().flatten() {
+                action.file.iter_mut().for_each(resolve);
+            }
+        }
+    }
+
+    /// Replaces `${VAR}` and `${VAR:-default}` in the string settings of the
+    /// watchers and their actions with the values of variables looked up by
+    /// `lookup`. The values in the `[env]` table are interpolated first, and
+    /// then override the variables looked up for the watchers. Returns the
+    /// problems found, like references to undefined variables.
+    pub fn interpolate(&mut self, lookup: Lookup) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        for (name, value) in self.env.iter_mut().flatten() {
+            interpolate_setting(value, lookup, "[env]", name, &mut problems);
+        }
+
+        let env = self.env.clone().unwrap_or_default();
+        let lookup = |name: &str| env.get(name).cloned().or_else(|| lookup(name));
+        for (index, watcher) in self.watchers.iter_mut().flatten().enumerate() {
+            watcher.interpolate(&lookup, index, &mut problems);
+        }
+
+        problems
+    }
+
+    /// Checks the config for problems that TOML parsing doesn't catch, like
+    /// unknown keys, unknown action types, watchers without paths and
+    /// invalid placeholders, and returns all of them.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        unknown_keys(&self.unknown, "", &mut problems);
+        if let Some(settings) = &self.settings {
+            unknown_keys(&settings.unknown, " in [settings]", &mut problems);
+        }
+
+        let watchers = self.watchers.as_deref().unwrap_or_default();
+        if watchers.is_empty() {
+            problems.push(ConfigProblem::new("no watchers defined"));
+        }
+        for (index, watcher) in watchers.iter().enumerate() {
+            watcher.validate(index, &mut problems);
+        }
+
+        problems
+    }
+}
+
+impl WatcherSettings {
+    fn interpolate(&mut self, lookup: Lookup, index: usize, problems: &mut Vec<ConfigProblem>) {
+        let location = format!("watcher {}", index + 1);
+        let mut setting = |key: &str, value: &mut String| {
+            interpolate_setting(value, lookup, &location, key, problems);
+        };
+
+        self.path.iter_mut().for_each(|path| setting("path", path));
+        self.paths
+            .iter_mut()
+            .flatten()
+            .for_each(|path| setting("paths", path));
+        self.execute
+            .iter_mut()
+            .for_each(|execute| setting("execute", execute));
+        self.include
+            .iter_mut()
+            .flatten()
+            .for_each(|glob| setting("include", glob));
+        self.exclude
+            .iter_mut()
+            .flatten()
+            .for_each(|glob| setting("exclude", glob));
+        if let Some(ShellSetting::Program(program)) = &mut self.shell {
+            setting("shell", program);
+        }
+        self.cwd.iter_mut().for_each(|cwd| setting("cwd", cwd));
+        for (name, value) in self.env.iter_mut().flatten() {
+            setting(&format!("env {}", name), value);
+        }
+
+        for (number, action) in self.actions.iter_mut().flatten().enumerate() {
+            let location = format!("{}, action {}", location, number + 1);
+            action.interpolate(lookup, &location, problems);
+        }
+    }
+
+    fn validate(&self, index: usize, problems: &mut Vec<ConfigProblem>) {
+        let location = format!("watcher {}", index + 1);
+        unknown_keys(&self.unknown, &format!(" in {}", location), problems);
+
+        if self.all_paths().is_empty() {
+            problems.push(ConfigProblem::new(format!("{} has no paths", location)));
+        }
+        if self.execute.is_some() && self.action_type.is_none() {
+            problems.push(ConfigProblem::new(format!(
+                "{} has an execute setting but no action_type",
+                location
+            )));
+        }
+
+        let include = self.include.as_deref().unwrap_or_default();
+        let exclude = self.exclude.as_deref().unwrap_or_default();
+        if let Err(error) = PathFilter::new(include, exclude) {
+            problems.push(ConfigProblem::new(format!("{}: {}", location, error)));
+        }
+        for name in self.events.iter().flatten() {
+            if EventType::from_name(name).is_none() {
+                problems.push(ConfigProblem::new(format!(
+                    "{}: unknown event type \"{}\", expected {}",
+                    location, name, EVENT_TYPE_NAMES
+                )));
+            }
+        }
+        if let Some(name) = self.on_busy.as_deref() {
+            if QueuePolicy::from_name(name).is_none() {
+                problems.push(ConfigProblem::new(format!(
+                    "{}: unknown on_busy policy \"{}\", expected queue, drop-new or restart",
+                    location, name
+                )));
+            }
+        }
+        if let Some(name) = self.on_failure.as_deref() {
+            if FailurePolicy::from_name(name).is_none() {
+                problems.push(ConfigProblem::new(format!(
+                    "{}: unknown on_failure policy \"{}\", expected continue, stop or exit",
+                    location, name
+                )));
+            }
+        }
+
+        // The action set directly on the watcher is not in the actions
+        // list, so the list is numbered from 1 after it.
+        let first_number = if self.action_type.is_some() { 0 } else { 1 };
+        for (number, action) in self.all_actions().iter().enumerate() {
+            let location = match number + first_number {
+                0 => location.clone(),
+                number => format!("{}, action {}", location, number),
+            };
+            action.validate(&location, self.shell.as_ref(), problems);
+        }
+    }
+}
+
+impl ActionSettings {
+    fn interpolate(&mut self, lookup: Lookup, location: &str, problems: &mut Vec<ConfigProblem>) {
+        let mut setting = |key: &str, value: &mut String| {
+            interpolate_setting(value, lookup, location, key, problems);
+        };
+
+        self.execute
+            .iter_mut()
+            .for_each(|execute| setting("execute", execute));
+        self.file.iter_mut().for_each(|file| setting("file", file));
+        if let Some(ShellSetting::Program(program)) = &mut self.shell {
+            setting("shell", program);
+        }
+    }
+
+    /// Checks the action for problems. `watcher_shell` is the shell setting
+    /// of its watcher, which applies if the action has none of its own.
+    fn validate(
+        &self,
+        location: &str,
+        watcher_shell: Option<&ShellSetting>,
+        problems: &mut Vec<ConfigProblem>,
+    ) {
+        unknown_keys(&self.unknown, &format!(" in {}", location), problems);
+
+        let action_type = self.action_type.as_str();
+        if !ACTION_TYPES.contains(&action_type) {
+            problems.push(ConfigProblem::new(format!(
+                "{}: unknown action_type \"{}\", expected {}",
+                location,
+                action_type,
+                ACTION_TYPES.join(", ")
+            )));
+        }
+
+        if action_type == "command" || action_type == "restart" {
+            match &self.execute {
+                Some(execute) => {
+                    // Without a shell, the command line is split into
+                    // arguments by eagle.
+                    let shell = self.shell.as_ref().or(watcher_shell);
+                    let split = match shell {
+                        None | Some(ShellSetting::Enabled(false)) => shell_words::split(execute)
+                            .map(|_| ())
+                            .map_err(|e| e.to_string()),
+                        Some(_) => Ok(()),
+                    };
+                    if let Err(error) = Template::parse(execute).and(split) {
+                        problems.push(ConfigProblem::new(format!(
+                            "{}: invalid command \"{}\": {}",
+                            location, execute, error
+                        )));
+                    }
+                }
+                None => problems.push(ConfigProblem::new(format!(
+                    "{}: the {} action has no execute setting",
+                    location, action_type
+                ))),
+            }
+        }
+        if action_type == "log" && self.file.is_none() {
+            problems.push(ConfigProblem::new(format!(
+                "{}: the log action has no file setting",
+                location
+            )));
+        }
+        if let Some(signal) = self.signal.as_deref() {
+            if restart::parse_signal(signal).is_none() {
+                problems.push(ConfigProblem::new(format!(
+                    "{}: unknown signal \"{}\"",
+                    location, signal
+                )));
+            }
+        }
+    }
+}
+
+fn interpolate_setting(
+    value: &mut String,
+    lookup: Lookup,
+    location: &str,
+    key: &str,
+    problems: &mut Vec<ConfigProblem>,
+) {
+    match interpolate::interpolate(value, lookup) {
+        Ok(interpolated) => *value = interpolated,
+        Err(errors) => problems.extend(
+            errors
+                .into_iter()
+                .map(|error| ConfigProblem::new(format!("{}: {} in {}", location, error, key))),
+        ),
+    }
+}
+
+fn unknown_keys(unknown: &UnknownKeys, location: &str, problems: &mut Vec<ConfigProblem>) {
+    for key in unknown.keys() {
+        problems.push(ConfigProblem::new(format!(
+            "unknown key \"{}\"{}",
+            key, location
+        )));
+    }
+}
+
+/// Parses and validates config content, failing with all problems found.
+pub fn parse(config_content: String) -> Result<Config> {
+    parse_content(&config_content, None)
+}
+
+/// Looks for a config file to use when none was given. The first
+/// `.eagle.toml` or `eagle.toml` in `dir` or one of its ancestors is used,
+/// and otherwise the user's `$XDG_CONFIG_HOME/eagle/config.toml`.
+pub fn find_config_file(dir: &Path) -> Option<PathBuf> {
+    find_in_ancestors(dir).or_else(|| {
+        let path = user_config_path(env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME"))?;
+        path.is_file().then_some(path)
+    })
+}
+
+fn find_in_ancestors(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| CONFIG_FILE_NAMES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// Returns the path of the user's config file. `XDG_CONFIG_HOME` defaults
+/// to `~/.config`, and is ignored if it's relative, as the XDG spec says.
+fn user_config_path(xdg_config_home: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+    let config_home = match xdg_config_home.map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path,
+        _ => PathBuf::from(home?).join(".config"),
+    };
+
+    Some(config_home.join("eagle").join("config.toml"))
+}
+
+/// Reads, parses and validates a config file, failing with all problems
+/// found. Relative paths in it are resolved against its directory.
+pub fn parse_file(path: &Path) -> Result<Config> {
+    let config_content = match fs::read_to_string(path) {
+        Ok(config_content) => config_content,
+        Err(error) => {
+            return Err(Error::Config {
+                path: Some(path.to_path_buf()),
+                problems: vec![ConfigProblem::new(error.to_string())],
+                source: Some(Box::new(error)),
+            })
+        }
+    };
+
+    let mut config = parse_content(&config_content, Some(path))?;
+    let absolute_path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    if let Some(base_dir) = absolute_path.parent() {
+        config.resolve_paths(base_dir);
+    }
+
+    Ok(config)
+}
+
+fn parse_content(config_content: &str, path: Option<&Path>) -> Result<Config> {
+    let mut config: Config = match toml::from_str(config_content) {
+        Ok(config) => config,
+        Err(error) => {
+            let problem = match error.span() {
+                Some(span) => ConfigProblem::at(config_content, span.start, error.message()),
+                None => ConfigProblem::new(error.message()),
+            };
+            return Err(Error::Config {
+                path: path.map(Path::to_path_buf),
+                problems: vec![problem],
+                source: Some(Box::new(error)),
+            });
+        }
+    };
+
+    let mut problems = config.interpolate(&|name| env::var(name).ok());
+    problems.extend(config.validate());
+    if !problems.is_empty() {
+        return Err(Error::Config {
+            path: path.map(Path::to_path_buf),
+            problems,
+            source: None,
+        });
     }
 
-    let mut config_content = String::new();
-    File::open(path)
-        .and_then(|mut f| f.read_to_string(&mut config_content))
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_watcher_with_one_path_and_action() {
+        let config = parse(
+            r#"
+            [[watchers]]
+            action_type = "command"
+            execute = "make"
+            path = "/tmp/src"
+            "#
+            .to_string(),
+        )
+        .unwrap();
+
+        let watcher = &config.watchers.unwrap()[0];
+        assert_eq!(vec!["/tmp/src".to_string()], watcher.all_paths());
+
+        let actions = watcher.all_actions();
+        assert_eq!(1, actions.len());
+        assert_eq!("command", actions[0].action_type);
+        assert_eq!(Some("make".to_string()), actions[0].execute);
+    }
+
+    #[test]
+    fn parse_watcher_with_paths_and_actions() {
+        let config = parse(
+            r#"
+            [[watchers]]
+            paths = ["/tmp/docs", "/tmp/src"]
+
+            [[watchers.actions]]
+            action_type = "print"
+
+            [[watchers.actions]]
+            action_type = "command"
+            execute = "make docs"
+
+            [[watchers.actions]]
+            action_type = "log"
+            file = "/tmp/eagle.log"
+            "#
+            .to_string(),
+        )
+        .unwrap();
+
+        let watcher = &config.watchers.unwrap()[0];
+        assert_eq!(
+            vec!["/tmp/docs".to_string(), "/tmp/src".to_string()],
+            watcher.all_paths()
+        );
+
+        let action_types: Vec<String> = watcher
+            .all_actions()
+            .into_iter()
+            .map(|action| action.action_type)
+            .collect();
+        assert_eq!(vec!["print", "command", "log"], action_types);
+    }
+
+    #[test]
+    fn parse_shell_settings() {
+        let config = parse(
+            r#"
+            [[watchers]]
+            path = "/tmp/src"
+            shell = true
+
+            [[watchers.actions]]
+            action_type = "command"
+            execute = "make | tee build.log"
+            shell = "bash"
+            "#
+            .to_string(),
+        )
+        .unwrap();
+
+        let watcher = &config.watchers.unwrap()[0];
+        assert_eq!(Some(ShellSetting::Enabled(true)), watcher.shell);
+        assert_eq!(
+            Some(ShellSetting::Program("bash".to_string())),
+            watcher.all_actions()[0].shell
+        );
+    }
+
+    #[test]
+    fn parse_invalid_toml() {
+        let error = parse("[[watchers]\npath = 1".to_string()).unwrap_err();
+        assert!(matches!(error, Error::Config { path: None, .. }));
+    }
+
+    #[test]
+    fn report_syntax_error_position() {
+        let error = parse("[[watchers]]\npath = 1\n".to_string()).unwrap_err();
+        match error {
+            Error::Config { problems, .. } => {
+                assert_eq!(1, problems.len());
+                assert_eq!(Some(2), problems[0].line);
+                assert_eq!(Some(8), problems[0].column);
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn report_all_problems() {
+        let error = parse(
+            r#"
+            [settings]
+            quiet = true
+            debounce = 100
+
+            [[watchers]]
+            recursve = false
+            on_busy = "wait"
+
+            [[watchers.actions]]
+            action_type = "command"
+
+            [[watchers.actions]]
+            action_type = "email"
+            execute = "make {:nope}"
+
+            [[watchers]]
+            path = "/tmp/src"
+            exclude = ["[a"]
+
+            [[watchers.actions]]
+            action_type = "log"
+            "#
+            .to_string(),
+        )
+        .unwrap_err();
+
+        let messages: Vec<String> = match error {
+            Error::Config { problems, .. } => problems
+                .into_iter()
+                .map(|problem| problem.to_string())
+                .collect(),
+            error => panic!("unexpected error: {}", error),
+        };
+        assert_eq!(
+            vec![
+                "unknown key \"debounce\" in [settings]",
+                "unknown key \"recursve\" in watcher 1",
+                "watcher 1 has no paths",
+                "watcher 1: unknown on_busy policy \"wait\", expected queue, drop-new or restart",
+                "watcher 1, action 1: the command action has no execute setting",
+                "watcher 1, action 2: unknown action_type \"email\", expected print, command, restart, log",
+            ],
+            messages[..6].to_vec()
+        );
+        assert!(messages[6].starts_with("watcher 2: "));
+        assert_eq!(
+            "watcher 2, action 1: the log action has no file setting",
+            messages[7]
+        );
+        assert_eq!(8, messages.len());
+    }
+
+    #[test]
+    fn report_missing_watchers() {
+        let error = parse("[settings]\nquiet = true\n".to_string()).unwrap_err();
+        assert_eq!("invalid config: no watchers defined", error.to_string());
+    }
+
+    #[test]
+    fn report_unknown_event_types() {
+        let error = parse(
+            r#"
+            [[watchers]]
+            path = "/tmp/uploads"
+            events = ["access_close_write", "write", "delete"]
+            action_type = "print"
+            "#
+            .to_string(),
+        )
+        .unwrap_err();
+
+        let messages: Vec<String> = match error {
+            Error::Config { problems, .. } => problems
+                .into_iter()
+                .map(|problem| problem.to_string())
+                .collect(),
+            error => panic!("unexpected error: {}", error),
+        };
+        let expected = |name| {
+            format!(
+                "watcher 1: unknown event type \"{}\", expected {}",
+                name, EVENT_TYPE_NAMES
+            )
+        };
+        assert_eq!(vec![expected("write"), expected("delete")], messages);
+    }
+
+    #[test]
+    fn report_unbalanced_quotes_without_shell() {
+        let error = parse(
+            r#"
+            [[watchers]]
+            path = "/tmp/src"
+            action_type = "command"
+            execute = "echo 'oops"
+
+            [[watchers]]
+            path = "/tmp/docs"
+            shell = true
+            action_type = "command"
+            execute = "echo 'oops"
+
+            [[watchers.actions]]
+            action_type = "restart"
+            shell = false
+            execute = "echo 'oops"
+            "#
+            .to_string(),
+        )
+        .unwrap_err();
+
+        let messages: Vec<String> = match error {
+            Error::Config { problems, .. } => problems
+                .into_iter()
+                .map(|problem| problem.to_string())
+                .collect(),
+            error => panic!("unexpected error: {}", error),
+        };
+        assert_eq!(
+            vec![
+                "watcher 1: invalid command \"echo 'oops\": missing closing quote",
+                "watcher 2, action 1: invalid command \"echo 'oops\": missing closing quote",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn diff_reloaded_watchers() {
+        let watchers = |content: &str| parse(content.to_string()).unwrap().watchers.unwrap();
+        let old = watchers(
+            r#"
+            [[watchers]]
+            path = "/tmp/src"
+            action_type = "command"
+            execute = "make"
+
+            [[watchers]]
+            path = "/tmp/docs"
+            action_type = "print"
+
+            [[watchers]]
+            paths = ["/tmp/a", "/tmp/b"]
+            action_type = "print"
+            "#,
+        );
+        let new = watchers(
+            r#"
+            [[watchers]]
+            paths = ["/tmp/b", "/tmp/a"]
+            action_type = "print"
+
+            [[watchers]]
+            path = "/tmp/src"
+            action_type = "command"
+            execute = "make all"
+
+            [[watchers]]
+            path = "/tmp/tests"
+            action_type = "print"
+            "#,
+        );
+
+        assert_eq!(
+            vec![
+                WatcherDiff::Removed { old: 1 },
+                WatcherDiff::Changed { old: 2, new: 0 },
+                WatcherDiff::Changed { old: 0, new: 1 },
+                WatcherDiff::Added { new: 2 },
+            ],
+            diff_watchers(&old, &new)
+        );
+        assert_eq!(
+            vec![
+                WatcherDiff::Unchanged { old: 0, new: 0 },
+                WatcherDiff::Unchanged { old: 1, new: 1 },
+            ],
+            diff_watchers(&new[..2], &new[..2])
+        );
+        assert!(new[1].has_other_actions(&old[0]));
+        assert!(!new[0].has_other_actions(&old[2]));
+    }
+
+    fn interpolated(content: &str) -> (Config, Vec<ConfigProblem>) {
+        let mut config: Config = toml::from_str(content).unwrap();
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/eagle".to_string()),
+            _ => None,
+        };
+        let problems = config.interpolate(&lookup);
+
+        (config, problems)
+    }
+
+    #[test]
+    fn interpolate_variables() {
+        let (config, problems) = interpolated(
+            r#"
+            [env]
+            BUILD_DIR = "${HOME}/build"
+
+            [[watchers]]
+            path = "${HOME}/src"
+            cwd = "${BUILD_DIR}"
+            env = { CC = "${CC:-cc}" }
+            action_type = "command"
+            execute = "make -C ${BUILD_DIR} ${TARGET:-all} $$PATH ${EAGLE_PATH}"
+
+            [[watchers.actions]]
+            action_type = "log"
+            file = "${LOG_DIR:-/tmp}/eagle.log"
+            "#,
+        );
+        assert_eq!(Vec::<ConfigProblem>::new(), problems);
+
+        let env = config.env.unwrap();
+        assert_eq!("/home/eagle/build", env["BUILD_DIR"]);
+
+        let watcher = &config.watchers.unwrap()[0];
+        assert_eq!(vec!["/home/eagle/src"], watcher.all_paths());
+        assert_eq!(Some("/home/eagle/build".to_string()), watcher.cwd);
+        assert_eq!("cc", watcher.env.as_ref().unwrap()["CC"]);
+        let actions = watcher.all_actions();
+        assert_eq!(
+            Some("make -C /home/eagle/build all $$PATH ${EAGLE_PATH}".to_string()),
+            actions[0].execute
+        );
+        assert_eq!(Some("/tmp/eagle.log".to_string()), actions[1].file);
+    }
+
+    #[test]
+    fn report_undefined_variables() {
+        let (_, problems) = interpolated(
+            r#"
+            [env]
+            CACHE = "${XDG_CACHE_HOME}/eagle"
+
+            [[watchers]]
+            paths = ["${HOME}/src", "${PROJECT}/docs"]
+
+            [[watchers.actions]]
+            action_type = "command"
+            execute = "make ${TARGET}"
+            "#,
+        );
+
+        let messages: Vec<String> = problems.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            vec![
+                "[env]: undefined variable XDG_CACHE_HOME in CACHE",
+                "watcher 1: undefined variable PROJECT in paths",
+                "watcher 1, action 1: undefined variable TARGET in execute",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn resolve_relative_paths() {
+        let mut config = parse(
+            r#"
+            [[watchers]]
+            path = "src"
+            paths = ["/tmp/docs", "../tests"]
+            cwd = "."
+
+            [[watchers.actions]]
+            action_type = "log"
+            file = "eagle.log"
+            "#
+            .to_string(),
+        )
         .unwrap();
+        config.resolve_paths(Path::new("/tmp/project"));
 
-    parse(config_content)
+        let watcher = &config.watchers.unwrap()[0];
+        assert_eq!(
+            vec!["/tmp/project/src", "/tmp/docs", "/tmp/project/../tests"],
+            watcher.all_paths()
+        );
+        assert_eq!(Some("/tmp/project/.".to_string()), watcher.cwd);
+        assert_eq!(
+            Some("/tmp/project/eagle.log".to_string()),
+            watcher.all_actions()[0].file
+        );
+    }
+
+    #[test]
+    fn find_config_file_in_ancestors() {
+        let root = env::temp_dir().join(format!("eagle-config-test-{}", std::process::id()));
+        let nested = root.join("project").join("src");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.join("eagle.toml"), "").unwrap();
+        assert_eq!(Some(root.join("eagle.toml")), find_in_ancestors(&nested));
+
+        fs::write(root.join("project").join("eagle.toml"), "").unwrap();
+        fs::write(root.join("project").join(".eagle.toml"), "").unwrap();
+        assert_eq!(
+            Some(root.join("project").join(".eagle.toml")),
+            find_in_ancestors(&nested)
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn user_config_path_from_environment() {
+        let home = Some(OsString::from("/home/eagle"));
+        assert_eq!(
+            Some(PathBuf::from("/etc/xdg/eagle/config.toml")),
+            user_config_path(Some(OsString::from("/etc/xdg")), home.clone())
+        );
+        assert_eq!(
+            Some(PathBuf::from("/home/eagle/.config/eagle/config.toml")),
+            user_config_path(Some(OsString::from("relative")), home.clone())
+        );
+        assert_eq!(
+            Some(PathBuf::from("/home/eagle/.config/eagle/config.toml")),
+            user_config_path(None, home)
+        );
+        assert_eq!(None, user_config_path(None, None));
+    }
+
+    #[test]
+    fn parse_missing_file() {
+        let path = Path::new("/does/not/exist/eagle.toml");
+        match parse_file(path).unwrap_err() {
+            Error::Config {
+                path: error_path,
+                source,
+                ..
+            } => {
+                assert_eq!(Some(path.to_path_buf()), error_path);
+                assert!(source.is_some());
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+    }
 }
@@ -0,0 +1,261 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::result;
+use std::time::Duration;
+
+/// Everything that can go wrong while setting up watches or running
+/// actions.
+#[derive(Debug)]
+pub enum Error {
+    /// A config file could not be read or is invalid. All problems found in
+    /// it are listed. `path` is not set for config content that didn't come
+    /// from a file.
+    Config {
+        path: Option<PathBuf>,
+        problems: Vec<ConfigProblem>,
+        source: Option<Box<dyn error::Error + Send + Sync>>,
+    },
+
+    /// The command line of a command action can't be parsed, for example
+    /// because of an unknown placeholder or an unbalanced quote.
+    InvalidCommand { command: String, message: String },
+
+    /// The path is already watched, possibly under a different name.
+    AlreadyWatched { path: PathBuf },
+
+    /// The file event backend could not watch the path.
+    Watch {
+        path: PathBuf,
+        source: notify::Error,
+    },
+
+    /// The file event backend reported an error, or stopped sending events
+    /// if there is no source.
+    EventBackend { source: Option<notify::Error> },
+
+    /// The process of an action could not be started or waited for.
+    ActionSpawn { command: String, source: io::Error },
+
+    /// The process of an action exited unsuccessfully.
+    ActionExitStatus { command: String, status: ExitStatus },
+
+    /// The process of an action ran for longer than its timeout and was
+    /// stopped.
+    Timeout { command: String, timeout: Duration },
+
+    /// The action was cancelled while it was running.
+    Cancelled { command: String },
+
+    /// A file written by an action, like a log file, could not be written.
+    ActionIo { path: PathBuf, source: io::Error },
+
+    /// The action was handed a change without any paths.
+    NoPaths,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// A single problem found in a config file. The line and column start at 1,
+/// and are only known for syntax errors and values of the wrong type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    pub fn new(message: impl Into<String>) -> ConfigProblem {
+        ConfigProblem {
+            line: None,
+            column: None,
+            message: message.into(),
+        }
+    }
+
+    /// Creates a problem at a byte offset into the config content.
+    pub fn at(content: &str, offset: usize, message: impl Into<String>) -> ConfigProblem {
+        let before = &content[..offset.min(content.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+        let column = before[line_start..].chars().count() + 1;
+
+        ConfigProblem {
+            line: Some(line),
+            column: Some(column),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => {
+                write!(f, "line {}, column {}: {}", line, column, self.message)
+            }
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error {
+    /// Creates a config error with a single problem and no underlying
+    /// cause.
+    pub fn config(path: Option<PathBuf>, message: impl Into<String>) -> Error {
+        Error::Config {
+            path,
+            problems: vec![ConfigProblem::new(message)],
+            source: None,
+        }
+    }
+
+    /// Returns the exit code to exit eagle with because of this error. A
+    /// failed command's own exit code is used, or 128 plus the number of the
+    /// signal that terminated it. Other errors use 1.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::ActionExitStatus { status, .. } => match status.code() {
+                Some(code) => code,
+                None => 128 + signal_of(status).unwrap_or(0),
+            },
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn signal_of(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal_of(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config { path, problems, .. } => {
+                match path {
+                    Some(path) => write!(f, "invalid config file {:?}:", path)?,
+                    None => write!(f, "invalid config:")?,
+                }
+                match problems.as_slice() {
+                    [problem] => write!(f, " {}", problem),
+                    problems => problems
+                        .iter()
+                        .try_for_each(|problem| write!(f, "\n  {}", problem)),
+                }
+            }
+            Error::InvalidCommand { command, message } => {
+                write!(f, "invalid command {:?}: {}", command, message)
+            }
+            Error::AlreadyWatched { path } => write!(f, "path {:?} is already watched", path),
+            Error::Watch { path, source } => {
+                write!(f, "could not watch path {:?}: {}", path, source)
+            }
+            Error::EventBackend {
+                source: Some(source),
+            } => write!(f, "error in file event: {}", source),
+            Error::EventBackend { source: None } => write!(f, "file events stopped arriving"),
+            Error::ActionSpawn { command, source } => {
+                write!(f, "could not execute command {:?}: {}", command, source)
+            }
+            Error::ActionExitStatus { command, status } => {
+                write!(f, "command {:?} failed: {}", command, status)
+            }
+            Error::Timeout { command, timeout } => {
+                write!(f, "command {:?} timed out after {:?}", command, timeout)
+            }
+            Error::Cancelled { command } => write!(f, "command {:?} was cancelled", command),
+            Error::ActionIo { path, source } => {
+                write!(f, "could not write to {:?}: {}", path, source)
+            }
+            Error::NoPaths => write!(f, "no path for event"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Config {
+                source: Some(source),
+                ..
+            } => Some(source.as_ref()),
+            Error::Watch { source, .. } => Some(source),
+            Error::EventBackend {
+                source: Some(source),
+            } => Some(source),
+            Error::ActionSpawn { source, .. } | Error::ActionIo { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::error::Error as _;
+
+    #[test]
+    fn display_config_error() {
+        let error = Error::config(Some(PathBuf::from("eagle.toml")), "no watchers");
+        assert_eq!(
+            "invalid config file \"eagle.toml\": no watchers",
+            error.to_string()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_code_of_failed_command() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let failed = |status| Error::ActionExitStatus {
+            command: "make".to_string(),
+            status,
+        };
+        assert_eq!(2, failed(ExitStatus::from_raw(2 << 8)).exit_code());
+        assert_eq!(128 + 9, failed(ExitStatus::from_raw(9)).exit_code());
+        assert_eq!(1, Error::NoPaths.exit_code());
+    }
+
+    #[test]
+    fn display_config_problems() {
+        let content = "[[watchers]]\npath = 1\n";
+        let error = Error::Config {
+            path: None,
+            problems: vec![
+                ConfigProblem::at(content, 20, "invalid type"),
+                ConfigProblem::new("watcher 1 has no paths"),
+            ],
+            source: None,
+        };
+        assert_eq!(
+            "invalid config:\n  line 2, column 8: invalid type\n  watcher 1 has no paths",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn source_of_spawn_error() {
+        let error = Error::ActionSpawn {
+            command: "make".to_string(),
+            source: io::Error::from(io::ErrorKind::NotFound),
+        };
+        assert!(error
+            .to_string()
+            .starts_with("could not execute command \"make\""));
+        assert!(error.source().is_some());
+        assert!(Error::NoPaths.source().is_none());
+    }
+}
@@ -1,19 +1,131 @@
 extern crate libc;
 extern crate notify;
 
-use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
-use std::sync::mpsc::{Receiver, RecvError};
+use notify::event::{CreateKind, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::actions::{self, Action, CancelToken, Change};
+use crate::error::{Error, Result};
+use crate::filter::{EventFilter, EventType, PathFilter};
+use crate::ignore_rules::IgnoreRules;
+use crate::worker_pool::{
+    self, ActionOutcome, FailurePolicy, Job, JobResult, QueuePolicy, WorkerPool,
+};
+
+/// Options that control how the paths of a watch are watched.
+#[derive(Clone, Debug, Default)]
+pub struct WatchOptions {
+    /// Watch the whole directory tree below each path, including
+    /// subdirectories that are created after the watch was added.
+    pub recursive: bool,
+
+    /// Only run the actions for changed paths that pass this filter.
+    pub filter: PathFilter,
+
+    /// Only run the actions for the kinds of events that pass this filter.
+    pub events: EventFilter,
+
+    /// Ignore changes to paths that are ignored by `.gitignore` and
+    /// `.ignore` files, the repository's exclude file, or the global git
+    /// excludes file.
+    pub respect_gitignore: bool,
+
+    /// How long to wait for further changes before running the actions. If
+    /// not set, the default debounce duration of the `FilesWatcher` is used.
+    pub debounce: Option<Duration>,
+
+    /// How many times the actions may run at the same time when they are
+    /// run on a worker pool. If not set, they run one at a time.
+    pub max_concurrent: Option<usize>,
+
+    /// What happens to a change that arrives while the actions are already
+    /// running `max_concurrent` times on a worker pool.
+    pub queue_policy: QueuePolicy,
+
+    /// What happens to the remaining actions for a change when one of them
+    /// fails.
+    pub on_failure: FailurePolicy,
+
+    /// Run the actions once when `FilesWatcher::queue_start_runs` is
+    /// called, before anything changed.
+    pub run_on_start: bool,
+}
+
+/// One or more watched paths that share their options and actions.
+struct Watch {
+    options: WatchOptions,
+    actions: Arc<Vec<Box<dyn Action>>>,
+}
+
+/// What the thread waiting in `wait_and_execute` is woken up by.
+enum Message {
+    Event(notify::Result<Event>),
+    Finished(JobResult),
+    Stop,
+}
+
+/// Asks a `FilesWatcher` to stop waiting for changes, from any thread. The
+/// next call of `wait_and_execute` returns a result with `stopped` set.
+#[derive(Clone)]
+pub struct StopHandle {
+    tx: Sender<Message>,
+}
 
-use std::collections::HashMap;
-use std::io;
-use std::path::PathBuf;
+impl StopHandle {
+    pub fn stop(&self) {
+        let _ = self.tx.send(Message::Stop);
+    }
+}
+
+/// A single watched file or directory of a watch.
+struct WatchedPath {
+    watch_id: usize,
+    ignore_rules: Option<IgnoreRules>,
+}
+
+impl WatchedPath {
+    /// Returns true if the actions should run for a change to `path`, which
+    /// is below the watched path `root`.
+    fn is_match(&self, options: &WatchOptions, root: &Path, path: &Path) -> bool {
+        if let Some(ignore_rules) = &self.ignore_rules {
+            ignore_rules.reload_if_ignore_file(path);
+            if ignore_rules.is_ignored(path) {
+                return false;
+            }
+        }
 
-use crate::actions::Action;
+        options.filter.is_match(&relative_path(root, path))
+    }
+}
 
 pub struct FilesWatcher {
     watcher: Box<RecommendedWatcher>,
-    rx: Receiver<Result<Event, notify::Error>>,
-    watches: HashMap<PathBuf, Vec<Box<dyn Action>>>,
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
+    pool: Option<WorkerPool>,
+    /// The watches by id. Ids are handed out in the order the watches are
+    /// added.
+    watches: BTreeMap<usize, Watch>,
+    paths: HashMap<PathBuf, WatchedPath>,
+    /// The watched paths that don't exist, with the closest existing
+    /// ancestor directory that is watched instead until they appear.
+    missing: HashMap<PathBuf, PathBuf>,
+    pending: BTreeMap<usize, PendingChanges>,
+    /// The tracker id and old path of the last rename whose new path has
+    /// not been seen yet.
+    rename_from: Option<(Option<usize>, PathBuf)>,
+    debounce: Duration,
+    /// Set if only the first change is handled.
+    single_change: bool,
+    /// Set once the first change was handled with `single_change` set.
+    change_handled: bool,
+    next_id: usize,
 }
 
 impl Default for FilesWatcher {
@@ -22,92 +134,919 @@ impl Default for FilesWatcher {
     }
 }
 
+#[derive(Debug, Default)]
 pub struct EventExecutionResult {
+    /// The number of actions that ran successfully.
     pub num_actions: usize,
+    /// The number of actions that were stopped because they ran for longer
+    /// than their timeout. They also count as failed.
+    pub num_timed_out: usize,
+    /// What happened when each action ran, in the order they ran. A command
+    /// that exits with a non-zero status counts as failed.
+    pub outcomes: Vec<ActionOutcome>,
+    /// Set if an action failed for a watch with `FailurePolicy::Exit`. The
+    /// caller should exit with this code.
+    pub exit_code: Option<i32>,
+    /// The id of the watch whose actions ran, if any ran.
+    pub watch_id: Option<usize>,
+    /// The changed paths the actions ran for.
+    pub paths: Vec<PathBuf>,
     pub was_file_changed: bool,
+    /// Set if a `StopHandle` asked to stop waiting for changes. The caller
+    /// should call `shutdown`.
+    pub stopped: bool,
+    /// Set if the deadline given to `wait_and_execute_until` passed before
+    /// any change arrived.
+    pub timed_out: bool,
+}
+
+impl EventExecutionResult {
+    /// Returns the outcomes of the actions that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &ActionOutcome> {
+        self.outcomes.iter().filter(|outcome| outcome.is_failure())
+    }
+}
+
+impl From<JobResult> for EventExecutionResult {
+    fn from(result: JobResult) -> Self {
+        EventExecutionResult {
+            num_actions: result.num_actions,
+            num_timed_out: result.num_timed_out,
+            outcomes: result.outcomes,
+            exit_code: result.exit_code,
+            watch_id: Some(result.watch_id),
+            paths: result.paths,
+            was_file_changed: true,
+            stopped: false,
+            timed_out: false,
+        }
+    }
 }
 
 impl FilesWatcher {
+    /// Creates a watcher. Panics if the file event backend can't be set up,
+    /// see `try_new`.
     pub fn new() -> FilesWatcher {
-        let (tx, rx) = std::sync::mpsc::channel();
-        let watcher = notify::recommended_watcher(tx);
+        Self::try_new().unwrap()
+    }
 
-        FilesWatcher {
-            watcher: Box::new(watcher.unwrap()),
+    /// Creates a watcher, failing if the file event backend can't be set
+    /// up, for example because the limit of inotify instances is reached.
+    pub fn try_new() -> Result<FilesWatcher> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let event_tx = tx.clone();
+        let watcher = notify::recommended_watcher(move |event_result| {
+            let _ = event_tx.send(Message::Event(event_result));
+        })
+        .map_err(|source| Error::EventBackend {
+            source: Some(source),
+        })?;
+
+        Ok(FilesWatcher {
+            watcher: Box::new(watcher),
+            tx,
             rx,
-            watches: HashMap::new(),
+            pool: None,
+            watches: BTreeMap::new(),
+            paths: HashMap::new(),
+            missing: HashMap::new(),
+            pending: BTreeMap::new(),
+            rename_from: None,
+            debounce: Duration::ZERO,
+            single_change: false,
+            change_handled: false,
+            next_id: 0,
+        })
+    }
+
+    /// Sets how long to wait for further changes before running the actions
+    /// of watches that don't set their own debounce duration. The default
+    /// is to run the actions for every change right away.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// Only handles a single change. Once the actions for the first change
+    /// that is due have been run or handed to the worker pool, other pending
+    /// changes are discarded and later ones ignored.
+    pub fn set_single_change(&mut self, single_change: bool) {
+        self.single_change = single_change;
+    }
+
+    /// Runs the actions on a pool of `num_workers` threads instead of on
+    /// the thread calling `wait_and_execute`, so changes keep being received
+    /// while actions run. How often the actions of a single watch can run at
+    /// the same time is limited by its `max_concurrent` and `queue_policy`
+    /// options.
+    pub fn set_workers(&mut self, num_workers: usize) {
+        let tx = self.tx.clone();
+        self.pool = Some(WorkerPool::new(num_workers, move |result| {
+            let _ = tx.send(Message::Finished(result));
+        }));
+    }
+
+    /// Returns a handle that makes `wait_and_execute` return, like when a
+    /// signal asks eagle to exit.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            tx: self.tx.clone(),
         }
     }
 
-    // TODO: accept a Vec of paths
-    pub fn add_file(&mut self, path: PathBuf, actions: Vec<Box<dyn Action>>) {
-        // TODO: Support running actions on all files in a directory tree.
-        let result = self.watcher.watch(&path, RecursiveMode::NonRecursive);
+    /// Stops watching all paths and discards pending changes. Actions that
+    /// are running on the worker pool get up to `timeout` to finish, and are
+    /// cancelled after that, which kills the processes they started. Then
+    /// the actions are dropped, which stops the processes of restart
+    /// actions. Returns true if the running actions finished in time.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        let paths: Vec<PathBuf> = self.paths.keys().cloned().collect();
+        self.unwatch_paths(&paths);
+        self.paths.clear();
+        self.pending.clear();
+
+        let finished = match self.pool.take() {
+            Some(pool) => pool.shutdown(timeout),
+            None => true,
+        };
+        self.watches.clear();
+
+        finished
+    }
+
+    /// Queues a change for each watch with the `run_on_start` option, so
+    /// the next calls of `wait_and_execute` run their actions right away.
+    /// The change has the kind `actions::START_KIND`, and the watched paths
+    /// of the watch as its paths.
+    pub fn queue_start_runs(&mut self) {
+        let now = Instant::now();
+        let watch_ids: Vec<usize> = self
+            .watches
+            .iter()
+            .filter(|(_, watch)| watch.options.run_on_start)
+            .map(|(watch_id, _)| *watch_id)
+            .collect();
 
-        if result.is_ok() {
-            println!("Watching file: {:?}", path);
-            self.watches.insert(path, actions);
-        } else {
-            println!("Error adding watch for file: {:?}", result.err());
+        for watch_id in watch_ids {
+            let mut paths = self.paths_of(watch_id);
+            paths.sort();
+            let Some(root) = paths.first().cloned() else {
+                continue;
+            };
+
+            // The change is due now, without waiting for the debounce
+            // duration.
+            let debounce = self.watches[&watch_id]
+                .options
+                .debounce
+                .unwrap_or(self.debounce);
+            let due = now.checked_sub(debounce).unwrap_or(now);
+            let mut changes = PendingChanges::new(actions::START_KIND, root, due);
+            for path in paths {
+                changes.add(actions::START_KIND, path, due);
+            }
+            self.pending.insert(watch_id, changes);
+        }
+    }
+
+    pub fn add_file(&mut self, path: PathBuf, actions: Vec<Box<dyn Action>>) -> Result<usize> {
+        self.add_watch(vec![path], WatchOptions::default(), actions)
+    }
+
+    /// Watches one or more files or directories, running the actions in
+    /// order when any of them change. If `options.recursive` is set, the
+    /// actions run for changes to any file in the directory trees below the
+    /// paths.
+    ///
+    /// Paths that don't exist yet, or that are removed later, are watched
+    /// through their closest existing ancestor directory. Once they appear,
+    /// they are watched themselves again and the actions run for a create
+    /// event.
+    ///
+    /// Relative paths are made absolute against the working directory, as
+    /// file events have absolute paths.
+    ///
+    /// Each path can only be watched once. Watches on nested paths are
+    /// allowed, and a change is handled by the watch on the closest path.
+    /// When the changes for several watches are due at the same time, their
+    /// actions run in the order the watches were added.
+    ///
+    /// The actions are started once the paths are watched. Returns the id of
+    /// the new watch.
+    pub fn add_watch(
+        &mut self,
+        paths: Vec<PathBuf>,
+        options: WatchOptions,
+        actions: Vec<Box<dyn Action>>,
+    ) -> Result<usize> {
+        let paths: Vec<PathBuf> = paths.iter().map(|path| absolute_path(path)).collect();
+        for (index, path) in paths.iter().enumerate() {
+            if self.is_watched(path) || paths[..index].contains(path) {
+                return Err(Error::AlreadyWatched { path: path.clone() });
+            }
+        }
+
+        self.watch_paths(&paths, options.recursive)?;
+        if let Err(error) = start_actions(&actions) {
+            self.unwatch_paths(&paths);
+            return Err(error);
+        }
+
+        let watch_id = self.next_id;
+        self.next_id += 1;
+
+        for path in paths {
+            // Missing paths were reported when they were watched through an
+            // ancestor.
+            if !self.missing.contains_key(&path) {
+                println!("Watching file: {:?}", path);
+            }
+            let watched = WatchedPath {
+                watch_id,
+                ignore_rules: ignore_rules_for(&path, &options),
+            };
+            self.paths.insert(path, watched);
+        }
+        let actions = Arc::new(actions);
+        self.watches.insert(watch_id, Watch { options, actions });
+
+        Ok(watch_id)
+    }
+
+    /// Stops watching the paths of a watch and drops its actions. Pending
+    /// changes for it are discarded, while actions that are already running
+    /// on the worker pool finish first. Unknown ids are ignored.
+    pub fn remove_watch(&mut self, watch_id: usize) {
+        let paths = self.paths_of(watch_id);
+        self.unwatch_paths(&paths);
+        for path in paths {
+            println!("Stopped watching file: {:?}", path);
+            self.paths.remove(&path);
+        }
+
+        self.pending.remove(&watch_id);
+        if let Some(watch) = self.watches.remove(&watch_id) {
+            stop_actions(&watch.actions);
+        }
+    }
+
+    /// Replaces the options of a watch, watching its paths again if
+    /// `recursive` changed. If that fails, the old options are kept. Unknown
+    /// ids are ignored.
+    pub fn set_watch_options(&mut self, watch_id: usize, options: WatchOptions) -> Result<()> {
+        let recursive = match self.watches.get(&watch_id) {
+            Some(watch) => watch.options.recursive,
+            None => return Ok(()),
+        };
+
+        let paths = self.paths_of(watch_id);
+        if recursive != options.recursive {
+            self.unwatch_paths(&paths);
+            if let Err(error) = self.watch_paths(&paths, options.recursive) {
+                let _ = self.watch_paths(&paths, recursive);
+                return Err(error);
+            }
+        }
+
+        for path in &paths {
+            if let Some(watched) = self.paths.get_mut(path) {
+                watched.ignore_rules = ignore_rules_for(path, &options);
+            }
+        }
+        if let Some(watch) = self.watches.get_mut(&watch_id) {
+            watch.options = options;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the actions of a watch. The old actions are stopped before
+    /// the new ones are started, so a restarted process doesn't run twice,
+    /// even while changes on the worker pool still hold on to the old
+    /// actions. If one of the new actions can't be started, the old actions
+    /// are started again and kept. Unknown ids are ignored.
+    pub fn set_actions(&mut self, watch_id: usize, actions: Vec<Box<dyn Action>>) -> Result<()> {
+        let Some(watch) = self.watches.get_mut(&watch_id) else {
+            return Ok(());
+        };
+
+        stop_actions(&watch.actions);
+        if let Err(error) = start_actions(&actions) {
+            stop_actions(&actions);
+            if let Err(restart_error) = start_actions(&watch.actions) {
+                println!(
+                    "Error: could not start the previous actions again: {}",
+                    restart_error
+                );
+            }
+            return Err(error);
+        }
+        watch.actions = Arc::new(actions);
+
+        Ok(())
+    }
+
+    /// Returns the watched paths of a watch, sorted.
+    pub fn paths_of(&self, watch_id: usize) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self
+            .paths
+            .iter()
+            .filter(|(_, watched)| watched.watch_id == watch_id)
+            .map(|(path, _)| path.clone())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    /// Watches paths with the file event backend. If one of them can't be
+    /// watched, none of them are.
+    fn watch_paths(&mut self, paths: &[PathBuf], recursive: bool) -> Result<()> {
+        for (index, path) in paths.iter().enumerate() {
+            if let Err(source) = self.watch_path(path, recursive_mode(recursive)) {
+                self.unwatch_paths(&paths[..index]);
+                return Err(Error::Watch {
+                    path: path.clone(),
+                    source,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unwatch_paths(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            match self.missing.remove(path) {
+                Some(ancestor) => self.release_ancestor(&ancestor),
+                // The path stays watched while a missing path needs it.
+                None if self.missing.values().any(|ancestor| ancestor == path) => {}
+                None => {
+                    let _ = self.watcher.unwatch(path);
+                }
+            }
         }
     }
 
-    pub fn wait_for_events(&mut self) -> Result<Result<Event, notify::Error>, RecvError> {
-        self.rx.recv()
+    /// Watches a path with the file event backend, or its closest existing
+    /// ancestor directory if it doesn't exist.
+    fn watch_path(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+        if path.exists() {
+            return self.watcher.watch(path, mode);
+        }
+
+        let ancestor = match closest_existing_ancestor(path) {
+            Some(ancestor) => ancestor,
+            None => return self.watcher.watch(path, mode),
+        };
+        if !self.is_backend_watched(&ancestor) {
+            self.watcher.watch(&ancestor, RecursiveMode::NonRecursive)?;
+        }
+        println!(
+            "{:?} does not exist, watching {:?} until it does.",
+            path, ancestor
+        );
+        self.missing.insert(path.to_path_buf(), ancestor);
+
+        Ok(())
     }
 
-    pub fn wait_and_execute(&mut self) -> Result<EventExecutionResult, io::Error> {
-        let mut num_actions = 0;
-        let event_result = self.rx.recv();
+    /// Returns true if the file event backend watches `dir` for an existing
+    /// watched path, or for a watched path that doesn't exist yet.
+    fn is_backend_watched(&self, dir: &Path) -> bool {
+        (self.paths.contains_key(dir) && !self.missing.contains_key(dir))
+            || self.missing.values().any(|ancestor| ancestor == dir)
+    }
+
+    /// Stops watching a directory that was watched for a missing path, if
+    /// it isn't needed anymore.
+    fn release_ancestor(&mut self, ancestor: &Path) {
+        if !self.is_backend_watched(ancestor) {
+            let _ = self.watcher.unwatch(ancestor);
+        }
+    }
 
-        match event_result {
-            Err(_) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Error receiving event",
-            )),
-            Ok(event) => match event {
-                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Error in file event")),
-                Ok(event) => {
-                    if !is_file_changed_event(&event) {
-                        return Ok(EventExecutionResult {
-                            num_actions: 0,
-                            was_file_changed: false,
-                        });
+    /// Moves the watches for missing paths along when an event shows that
+    /// one of them, or a directory on the way to it, appeared or was
+    /// removed. Watched paths that were removed are watched through an
+    /// ancestor from now on. Returns the watched paths that exist again.
+    fn update_missing_paths(&mut self, event: &Event) -> Vec<PathBuf> {
+        let mut appeared = vec![];
+
+        // Editors that save by replacing a file remove it and create it
+        // again right away, so the path may already exist again.
+        if event.kind.is_remove() || matches!(event.kind, EventKind::Modify(ModifyKind::Name(_))) {
+            for path in event.paths.iter() {
+                if self.paths.contains_key(path) && !self.missing.contains_key(path) {
+                    let _ = self.watcher.unwatch(path);
+                    let mode = self.recursive_mode_of(path);
+                    let exists = path.exists();
+                    if self.watch_path(path, mode).is_ok() && exists {
+                        appeared.push(path.clone());
                     }
+                }
+            }
+        }
+
+        let affected: Vec<PathBuf> = self
+            .missing
+            .keys()
+            .filter(|missing| event.paths.iter().any(|path| missing.starts_with(path)))
+            .cloned()
+            .collect();
+        for path in affected {
+            if self.reattach(&path) {
+                appeared.push(path);
+            }
+        }
+
+        appeared
+    }
 
-                    if event.paths.is_empty() {
-                        println!("Warning: event has no paths");
-                        return Ok(EventExecutionResult {
-                            num_actions: 0,
-                            was_file_changed: true,
-                        });
+    /// Watches a missing path itself if it exists now, or otherwise through
+    /// its closest existing ancestor if that changed. Returns true if the
+    /// path exists and is watched again.
+    fn reattach(&mut self, path: &Path) -> bool {
+        let mode = self.recursive_mode_of(path);
+        while let Some(ancestor) = self.missing.get(path).cloned() {
+            if path.exists() {
+                if self.watcher.watch(path, mode).is_err() {
+                    return false;
+                }
+                self.missing.remove(path);
+                self.release_ancestor(&ancestor);
+                return true;
+            }
+
+            match closest_existing_ancestor(path) {
+                Some(closer) if closer != ancestor => {
+                    if !self.is_backend_watched(&closer)
+                        && self
+                            .watcher
+                            .watch(&closer, RecursiveMode::NonRecursive)
+                            .is_err()
+                    {
+                        return false;
                     }
+                    self.missing.insert(path.to_path_buf(), closer);
+                    self.release_ancestor(&ancestor);
+                    // The path may have appeared before the closer
+                    // directory was watched, so look again.
+                }
+                _ => return false,
+            }
+        }
+
+        false
+    }
 
-                    for path in event.paths.iter() {
-                        if let Some(actions) = self.watches.get(path) {
-                            for action in actions {
-                                if action.handle_change(&event).is_ok() {
-                                    num_actions += 1;
-                                }
+    fn recursive_mode_of(&self, path: &Path) -> RecursiveMode {
+        let recursive = self
+            .paths
+            .get(path)
+            .and_then(|watched| self.watches.get(&watched.watch_id))
+            .is_some_and(|watch| watch.options.recursive);
+        recursive_mode(recursive)
+    }
+
+    /// Returns true if `path` is already watched, including paths that
+    /// name the same file or directory in a different way.
+    fn is_watched(&self, path: &Path) -> bool {
+        if self.paths.contains_key(path) {
+            return true;
+        }
+
+        match path.canonicalize() {
+            Ok(canonical) => self
+                .paths
+                .keys()
+                .any(|watched| watched.canonicalize().ok().as_ref() == Some(&canonical)),
+            Err(_) => false,
+        }
+    }
+
+    /// Finds the watched path responsible for a changed path, and the watch
+    /// it belongs to. A watch on the path itself wins, followed by the
+    /// closest watched ancestor directory. A non-recursive directory watch
+    /// only covers its direct children.
+    fn find_watch<'a>(&self, path: &'a Path) -> Option<(&'a Path, &WatchedPath, &Watch)> {
+        for (depth, ancestor) in path.ancestors().enumerate() {
+            if let Some(watched) = self.paths.get(ancestor) {
+                let watch = &self.watches[&watched.watch_id];
+                if depth <= 1 || watch.options.recursive {
+                    return Some((ancestor, watched, watch));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Waits for the next file event. Actions that finish on the worker pool
+    /// in the meantime and requests to stop are not reported.
+    pub fn wait_for_events(&mut self) -> Result<Event> {
+        loop {
+            match self.rx.recv() {
+                Ok(Message::Event(Ok(event))) => return Ok(event),
+                Ok(Message::Event(Err(source))) => {
+                    return Err(Error::EventBackend {
+                        source: Some(source),
+                    })
+                }
+                Ok(Message::Finished(_)) | Ok(Message::Stop) => {}
+                Err(_) => return Err(Error::EventBackend { source: None }),
+            }
+        }
+    }
+
+    /// Waits until a change to a watched path is due and runs the actions
+    /// for it. Changes are collected until no new change has arrived for the
+    /// watch's debounce duration, and each action is then run once with all
+    /// of the changed paths.
+    ///
+    /// With a worker pool, due changes are handed to the pool instead, and
+    /// this returns once the actions for one of them have finished.
+    pub fn wait_and_execute(&mut self) -> Result<EventExecutionResult> {
+        self.wait_and_execute_with_deadline(None)
+    }
+
+    /// Like `wait_and_execute`, but gives up waiting at `deadline` if no
+    /// change to a watched path has arrived by then, and returns a result
+    /// with `timed_out` set. Once a change has arrived, its actions are run
+    /// even if that takes until after the deadline.
+    pub fn wait_and_execute_until(&mut self, deadline: Instant) -> Result<EventExecutionResult> {
+        self.wait_and_execute_with_deadline(Some(deadline))
+    }
+
+    fn wait_and_execute_with_deadline(
+        &mut self,
+        mut deadline: Option<Instant>,
+    ) -> Result<EventExecutionResult> {
+        loop {
+            if !self.pending.is_empty() || self.change_handled {
+                deadline = None;
+            }
+
+            let due = self.next_due();
+            if let Some((watch_id, due)) = due {
+                if due <= Instant::now() {
+                    if self.pool.is_some() {
+                        self.submit_pending(watch_id);
+                        continue;
+                    }
+                    return Ok(self.execute_pending(watch_id));
+                }
+            }
+
+            let wake_up = match (due.map(|(_, due)| due), deadline) {
+                (Some(due), Some(deadline)) => Some(due.min(deadline)),
+                (due, deadline) => due.or(deadline),
+            };
+            let message = match wake_up {
+                Some(wake_up) => {
+                    let timeout = wake_up.saturating_duration_since(Instant::now());
+                    match self.rx.recv_timeout(timeout) {
+                        Ok(message) => message,
+                        Err(RecvTimeoutError::Timeout) => {
+                            if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                                return Ok(EventExecutionResult {
+                                    timed_out: true,
+                                    ..Default::default()
+                                });
                             }
-                        } else {
-                            println!("Error: no actions found for path: {:?}", path.display());
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            return Err(Error::EventBackend { source: None })
                         }
                     }
+                }
+                None => match self.rx.recv() {
+                    Ok(message) => message,
+                    Err(_) => return Err(Error::EventBackend { source: None }),
+                },
+            };
+
+            let event_result = match message {
+                Message::Event(event_result) => event_result,
+                Message::Finished(result) => return Ok(result.into()),
+                Message::Stop => {
+                    return Ok(EventExecutionResult {
+                        stopped: true,
+                        ..Default::default()
+                    })
+                }
+            };
 
-                    Ok(EventExecutionResult {
-                        num_actions,
-                        was_file_changed: true,
+            let event = match event_result {
+                Ok(event) => event,
+                Err(source) => {
+                    return Err(Error::EventBackend {
+                        source: Some(source),
                     })
                 }
+            };
+
+            let was_file_changed = self.add_pending(event);
+            if self.pending.is_empty() {
+                return Ok(EventExecutionResult {
+                    was_file_changed,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    /// Adds the paths of an event to the pending changes of the watches they
+    /// belong to. Returns true if the event was a change to a file that was
+    /// not filtered out.
+    fn add_pending(&mut self, event: Event) -> bool {
+        if self.change_handled || !is_file_changed_event(&event) {
+            return false;
+        }
+
+        if event.paths.is_empty() {
+            println!("Warning: event has no paths");
+            return true;
+        }
+
+        // Watched paths that appeared count as created, even if the event
+        // was for removing a file that has been replaced since.
+        let appeared = self.update_missing_paths(&event);
+        let paths: Vec<PathBuf> = event
+            .paths
+            .iter()
+            .filter(|path| !appeared.contains(path))
+            .cloned()
+            .collect();
+        let create = if event.kind.is_create() {
+            event.kind
+        } else {
+            EventKind::Create(CreateKind::Any)
+        };
+        // Missing paths are updated for every kind of event, but the other
+        // paths only count if a watch runs its actions for the kind.
+        let mut was_file_changed = false;
+        if self.is_event_kind_watched(&event.kind) {
+            was_file_changed = self.add_pending_paths(event.kind, &paths);
+        }
+        was_file_changed |= self.add_pending_paths(create, &appeared);
+
+        if let EventKind::Modify(ModifyKind::Name(mode)) = event.kind {
+            self.track_rename(mode, &event);
+        }
+
+        was_file_changed
+    }
+
+    /// Returns true if any watch runs its actions for events of this kind.
+    /// Paths changed by kinds that no watch is interested in, like closing a
+    /// file after writing it with the default filter, are not reported.
+    fn is_event_kind_watched(&self, kind: &EventKind) -> bool {
+        self.watches
+            .values()
+            .any(|watch| watch.options.events.is_match(kind))
+    }
+
+    /// Adds changed paths to the pending changes of the watches they belong
+    /// to. Returns true if any of them was not filtered out.
+    fn add_pending_paths(&mut self, kind: EventKind, paths: &[PathBuf]) -> bool {
+        let mut was_file_changed = false;
+        let mut changes = vec![];
+        for path in paths.iter() {
+            if let Some((root, watched, watch)) = self.find_watch(path) {
+                if watched.is_match(&watch.options, root, path)
+                    && watch.options.events.is_match(&kind)
+                {
+                    was_file_changed = true;
+                    changes.push((watched.watch_id, root.to_path_buf(), path.clone()));
+                }
+            } else if !self.is_missing_ancestor_event(path) {
+                was_file_changed = true;
+                println!("Error: no actions found for path: {:?}", path.display());
+            }
+        }
+
+        // All paths share the same time, so watches with the same debounce
+        // duration are due at the same time.
+        let now = Instant::now();
+        for (watch_id, root, path) in changes {
+            self.pending
+                .entry(watch_id)
+                .or_insert_with(|| PendingChanges::new(kind, root, now))
+                .add(kind, path, now);
+        }
+
+        was_file_changed
+    }
+
+    /// Returns true if a changed path is in a directory that is only
+    /// watched for a missing path, so other changes in it don't matter.
+    fn is_missing_ancestor_event(&self, path: &Path) -> bool {
+        self.missing
+            .values()
+            .any(|ancestor| path == ancestor || path.parent() == Some(ancestor))
+    }
+
+    /// Pairs the old and new paths of renames, and adds them to the pending
+    /// changes of the watch of the new path. notify reports the two paths
+    /// of a rename in separate events, which share a tracker id on platforms
+    /// that provide one. On some platforms, a third event with both paths
+    /// follows.
+    fn track_rename(&mut self, mode: RenameMode, event: &Event) {
+        let (old, new) = match (mode, event.paths.as_slice()) {
+            (RenameMode::From, [old]) => {
+                self.rename_from = Some((event.tracker(), old.clone()));
+                return;
+            }
+            (RenameMode::To, [new]) => match self.rename_from.take() {
+                Some((tracker, old)) if tracker == event.tracker() => (old, new.clone()),
+                _ => return,
+            },
+            (RenameMode::Both, [old, new]) => (old.clone(), new.clone()),
+            _ => return,
+        };
+
+        let watch_id = match self.find_watch(&new) {
+            Some((_, watched, _)) => watched.watch_id,
+            None => return,
+        };
+        // The new path is not pending if the filters of the watch ignore it.
+        if let Some(changes) = self.pending.get_mut(&watch_id) {
+            let rename = (old, new);
+            if changes.paths.contains(&rename.1) && !changes.renames.contains(&rename) {
+                changes.renames.push(rename);
+            }
+        }
+    }
+
+    /// Returns the watch with the pending changes that are due next, and
+    /// the time they are due. If the changes for several watches are already
+    /// due, the watch that was added first goes first.
+    fn next_due(&self) -> Option<(usize, Instant)> {
+        let now = Instant::now();
+        self.pending
+            .iter()
+            .map(|(watch_id, changes)| {
+                let debounce = match self.watches.get(watch_id) {
+                    Some(watch) => watch.options.debounce.unwrap_or(self.debounce),
+                    None => Duration::ZERO,
+                };
+                (*watch_id, changes.last_change + debounce)
+            })
+            .min_by_key(|(watch_id, due)| ((*due).max(now), *watch_id))
+    }
+
+    /// Removes the pending changes of a watch and returns them as a single
+    /// change. With `single_change` set, the changes of other watches are
+    /// discarded.
+    fn take_pending(&mut self, watch_id: usize) -> Option<Change> {
+        let change = self.pending.remove(&watch_id)?.into_change();
+        if self.single_change {
+            self.change_handled = true;
+            self.pending.clear();
+        }
+
+        Some(change)
+    }
+
+    /// Hands the pending changes of a watch to the worker pool.
+    fn submit_pending(&mut self, watch_id: usize) {
+        let change = match self.take_pending(watch_id) {
+            Some(change) => change,
+            None => return,
+        };
+        let (watch, pool) = match (self.watches.get(&watch_id), &self.pool) {
+            (Some(watch), Some(pool)) => (watch, pool),
+            _ => return,
+        };
+
+        let job = Job {
+            watch_id,
+            change,
+            actions: watch.actions.clone(),
+            max_concurrent: watch.options.max_concurrent.unwrap_or(1),
+            policy: watch.options.queue_policy,
+            on_failure: watch.options.on_failure,
+        };
+        if !pool.submit(job) {
+            println!("Actions are still running, ignoring the change.");
+        }
+    }
+
+    fn execute_pending(&mut self, watch_id: usize) -> EventExecutionResult {
+        let change = match self.take_pending(watch_id) {
+            Some(change) => change,
+            None => return EventExecutionResult::default(),
+        };
+
+        match self.watches.get(&watch_id) {
+            Some(watch) => {
+                let cancel = CancelToken::new();
+                let on_failure = watch.options.on_failure;
+                worker_pool::run_actions(watch_id, &watch.actions, &change, on_failure, &cancel)
+                    .into()
+            }
+            None => EventExecutionResult {
+                was_file_changed: true,
+                ..Default::default()
             },
         }
     }
 }
 
-/// Returns true if the event is for a file change. Just opening or accessing a file does not count.
+fn ignore_rules_for(path: &Path, options: &WatchOptions) -> Option<IgnoreRules> {
+    if options.respect_gitignore {
+        Some(IgnoreRules::new(path))
+    } else {
+        None
+    }
+}
+
+/// Returns a path as an absolute path without `.` components, or the path
+/// itself if the working directory can't be determined.
+fn absolute_path(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn recursive_mode(recursive: bool) -> RecursiveMode {
+    if recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    }
+}
+
+/// Returns the closest ancestor of a path that is an existing directory.
+fn closest_existing_ancestor(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.is_dir())
+        .map(Path::to_path_buf)
+}
+
+fn start_actions(actions: &[Box<dyn Action>]) -> Result<()> {
+    actions.iter().try_for_each(|action| action.start())
+}
+
+fn stop_actions(actions: &[Box<dyn Action>]) {
+    actions.iter().for_each(|action| action.stop());
+}
+
+/// Changes to the paths of a single watch that have not been handed to its
+/// actions yet.
+struct PendingChanges {
+    kind: EventKind,
+    paths: Vec<PathBuf>,
+    renames: Vec<(PathBuf, PathBuf)>,
+    /// The watched path of the first change.
+    watch_root: PathBuf,
+    last_change: Instant,
+}
+
+impl PendingChanges {
+    fn new(kind: EventKind, watch_root: PathBuf, now: Instant) -> PendingChanges {
+        PendingChanges {
+            kind,
+            paths: vec![],
+            renames: vec![],
+            watch_root,
+            last_change: now,
+        }
+    }
+
+    /// Adds a changed path. The kind becomes the one returned by
+    /// `actions::merge_kinds`.
+    fn add(&mut self, kind: EventKind, path: PathBuf, now: Instant) {
+        self.kind = actions::merge_kinds(self.kind, kind);
+        if !self.paths.contains(&path) {
+            self.paths.push(path);
+        }
+        self.last_change = now;
+    }
+
+    fn into_change(self) -> Change {
+        let mut change = Change::new(self.kind, self.paths, self.watch_root);
+        change.renames = self.renames;
+        change
+    }
+}
+
+/// Returns the path of a changed file relative to the watched path it was
+/// found under. Changes to a watched file itself are relative to the
+/// directory containing it, so filters can match on its name.
+pub(crate) fn relative_path(root: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(root) {
+        Ok(relative) if relative.as_os_str().is_empty() => {
+            root.file_name().map(PathBuf::from).unwrap_or_default()
+        }
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Returns true if the event is of a kind that a watch can run its actions
+/// for. Just opening or reading a file does not count.
 fn is_file_changed_event(event: &Event) -> bool {
-    event.kind.is_modify() || event.kind.is_create() || event.kind.is_remove()
+    EventType::of(&event.kind).is_some()
 }
 
 #[cfg(test)]
@@ -118,17 +1057,20 @@ mod test {
 
     use self::rand::distributions::Alphanumeric;
     use self::rand::{thread_rng, Rng};
+    use crate::actions::command::CommandAction;
     use crate::actions::print::PrintAction;
     use crate::actions::Action;
     use notify::{event, EventKind};
     use std::env::temp_dir;
-    use std::fs::remove_file;
     use std::fs::File;
     use std::fs::OpenOptions;
+    use std::fs::{create_dir, remove_dir_all, remove_file};
     use std::io::Read;
     use std::io::Write;
     use std::path::Path;
     use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
 
     #[test]
     fn watch_a_single_file() {
@@ -137,12 +1079,12 @@ mod test {
 
         let mut fw = FilesWatcher::new();
         let actions: Vec<Box<dyn Action + 'static>> = Vec::new();
-        fw.add_file(path, actions);
+        fw.add_file(path, actions).unwrap();
 
         write_to(&mut file);
 
         {
-            let event = fw.wait_for_events().unwrap().unwrap();
+            let event = fw.wait_for_events().unwrap();
             if event.paths.is_empty() {
                 panic!("Error: event has no paths");
             }
@@ -164,20 +1106,20 @@ mod test {
         let actions2: Vec<Box<dyn Action + 'static>> = Vec::new();
 
         let mut fw = FilesWatcher::new();
-        fw.add_file(path1, actions1);
-        fw.add_file(path2, actions2);
+        fw.add_file(path1, actions1).unwrap();
+        fw.add_file(path2, actions2).unwrap();
 
         write_to(&mut file1);
         write_to(&mut file2);
 
         {
-            let event = fw.wait_for_events().unwrap().unwrap();
+            let event = fw.wait_for_events().unwrap();
             if event.paths.is_empty() {
                 panic!("Error: event has no paths");
             }
             assert_eq!(&filepath1, event.paths.first().unwrap());
 
-            let event = fw.wait_for_events().unwrap().unwrap();
+            let event = fw.wait_for_events().unwrap();
             if event.paths.is_empty() {
                 panic!("Error: event has no paths");
             }
@@ -195,7 +1137,7 @@ mod test {
 
         let mut fw = FilesWatcher::new();
         let actions: Vec<Box<dyn Action + 'static>> = Vec::new();
-        fw.add_file(path, actions);
+        fw.add_file(path, actions).unwrap();
 
         write_to(&mut file);
 
@@ -217,7 +1159,7 @@ mod test {
 
         let print = PrintAction::new();
         let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(print)];
-        fw.add_file(path, actions);
+        fw.add_file(path, actions).unwrap();
 
         read_file(&filepath);
 
@@ -239,7 +1181,7 @@ mod test {
 
         let print = PrintAction::new();
         let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(print)];
-        fw.add_file(path, actions);
+        fw.add_file(path, actions).unwrap();
 
         write_to(&mut file);
 
@@ -272,7 +1214,7 @@ mod test {
             Box::new(print4),
             Box::new(print5),
         ];
-        fw.add_file(path, actions);
+        fw.add_file(path, actions).unwrap();
 
         write_to(&mut file);
 
@@ -285,6 +1227,739 @@ mod test {
         remove_temp_file(&filepath);
     }
 
+    #[test]
+    fn watch_directory_recursively_and_execute() {
+        let dir = create_temp_dir();
+        let subdir = dir.join("subdir");
+
+        let mut fw = FilesWatcher::new();
+        let options = WatchOptions {
+            recursive: true,
+            ..Default::default()
+        };
+        let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(PrintAction::new())];
+        fw.add_watch(vec![dir.clone()], options, actions).unwrap();
+
+        // The subdirectory is created after the watch was added, so this
+        // also checks that new directories are picked up.
+        create_dir(&subdir).unwrap();
+        {
+            let execution_result = wait_for_change(&mut fw);
+            assert_eq!(1, execution_result.num_actions);
+        }
+
+        // The watch for the new directory is added by the notify backend
+        // after it reports the directory, so give it a moment.
+        sleep(Duration::from_millis(100));
+        File::create(subdir.join("nested.txt")).unwrap();
+        {
+            let execution_result = wait_for_change(&mut fw);
+            assert_eq!(1, execution_result.num_actions);
+        }
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_watch_non_recursive_directory() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        fw.add_watch(vec![dir.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+
+        assert!(fw.find_watch(&dir).is_some());
+        assert!(fw.find_watch(&dir.join("child")).is_some());
+        assert!(fw
+            .find_watch(&dir.join("child").join("grandchild"))
+            .is_none());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_watch_longest_prefix() {
+        let dir = create_temp_dir();
+        let subdir = dir.join("subdir");
+        create_dir(&subdir).unwrap();
+
+        let mut fw = FilesWatcher::new();
+        let outer: Vec<Box<dyn Action + 'static>> = vec![Box::new(PrintAction::new())];
+        let inner: Vec<Box<dyn Action + 'static>> = Vec::new();
+        fw.add_watch(
+            vec![dir.clone()],
+            WatchOptions {
+                recursive: true,
+                ..Default::default()
+            },
+            outer,
+        )
+        .unwrap();
+        fw.add_watch(
+            vec![subdir.clone()],
+            WatchOptions {
+                recursive: true,
+                ..Default::default()
+            },
+            inner,
+        )
+        .unwrap();
+
+        let outer_path = dir.join("a").join("b.txt");
+        let (root, _, watch) = fw.find_watch(&outer_path).unwrap();
+        assert_eq!(dir, root);
+        assert_eq!(1, watch.actions.len());
+        let inner_path = subdir.join("a").join("b.txt");
+        let (root, _, watch) = fw.find_watch(&inner_path).unwrap();
+        assert_eq!(subdir, root);
+        assert_eq!(0, watch.actions.len());
+        assert!(fw.find_watch(&temp_dir().join("unwatched")).is_none());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_directory_with_filter() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        let include = vec!["*.rs".to_string()];
+        let options = WatchOptions {
+            filter: PathFilter::new(&include, &[]).unwrap(),
+            ..Default::default()
+        };
+        let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(PrintAction::new())];
+        fw.add_watch(vec![dir.clone()], options, actions).unwrap();
+
+        File::create(dir.join("ignored.txt")).unwrap();
+        File::create(dir.join("main.rs")).unwrap();
+        {
+            // The first change that reaches the actions is the one for the
+            // file matching the filter.
+            let execution_result = wait_for_change(&mut fw);
+            assert_eq!(1, execution_result.num_actions);
+        }
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_directory_respecting_gitignore() {
+        let dir = create_temp_dir();
+        let mut gitignore = File::create(dir.join(".gitignore")).unwrap();
+        gitignore.write_all(b"*.log\n").unwrap();
+
+        let mut fw = FilesWatcher::new();
+        let options = WatchOptions {
+            respect_gitignore: true,
+            ..Default::default()
+        };
+        let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(PrintAction::new())];
+        fw.add_watch(vec![dir.clone()], options, actions).unwrap();
+
+        File::create(dir.join("build.log")).unwrap();
+        File::create(dir.join("main.rs")).unwrap();
+        {
+            let execution_result = wait_for_change(&mut fw);
+            assert_eq!(1, execution_result.num_actions);
+        }
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_directory_with_debounce() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        fw.set_debounce(Duration::from_millis(200));
+        let changes = Arc::new(Mutex::new(vec![]));
+        let record = RecordAction {
+            changes: changes.clone(),
+        };
+        let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(record)];
+        fw.add_watch(vec![dir.clone()], WatchOptions::default(), actions)
+            .unwrap();
+
+        for name in ["a.txt", "b.txt", "a.txt"] {
+            let mut file = File::create(dir.join(name)).unwrap();
+            write_to(&mut file);
+        }
+        {
+            let execution_result = wait_for_change(&mut fw);
+            assert_eq!(1, execution_result.num_actions);
+
+            let changes = changes.lock().unwrap();
+            assert_eq!(1, changes.len());
+            assert_eq!(vec![dir.join("a.txt"), dir.join("b.txt")], changes[0].paths);
+        }
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_two_paths_with_shared_actions() {
+        let (path1, mut file1) = create_temp_file();
+        let (path2, mut file2) = create_temp_file();
+
+        let mut fw = FilesWatcher::new();
+        let changes = Arc::new(Mutex::new(vec![]));
+        let record = RecordAction {
+            changes: changes.clone(),
+        };
+        let options = WatchOptions {
+            debounce: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(record)];
+        fw.add_watch(vec![path1.clone(), path2.clone()], options, actions)
+            .unwrap();
+
+        write_to(&mut file1);
+        write_to(&mut file2);
+        {
+            let execution_result = wait_for_change(&mut fw);
+            assert_eq!(1, execution_result.num_actions);
+
+            let changes = changes.lock().unwrap();
+            assert_eq!(vec![path1.clone(), path2.clone()], changes[0].paths);
+        }
+
+        remove_temp_file(&path1);
+        remove_temp_file(&path2);
+    }
+
+    #[test]
+    fn remove_watch_and_add_it_again() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        let first_id = fw
+            .add_watch(vec![dir.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+        fw.remove_watch(first_id);
+        assert!(fw.find_watch(&dir.join("main.rs")).is_none());
+
+        let second_id = fw
+            .add_watch(vec![dir.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+        assert_ne!(first_id, second_id);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stop_handle_stops_waiting() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        fw.add_watch(vec![dir.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+        fw.stop_handle().stop();
+        assert!(fw.wait_and_execute().unwrap().stopped);
+        assert!(fw.shutdown(Duration::from_secs(1)));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wait_until_deadline() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        fw.add_watch(vec![dir.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let execution_result = fw.wait_and_execute_until(deadline).unwrap();
+        assert!(execution_result.timed_out);
+        assert!(Instant::now() >= deadline);
+
+        File::create(dir.join("main.rs")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let execution_result = fw.wait_and_execute_until(deadline).unwrap();
+        assert!(!execution_result.timed_out);
+        assert_eq!(vec![dir.join("main.rs")], execution_result.paths);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn run_actions_on_start() {
+        let dir = create_temp_dir();
+        create_dir(dir.join("other")).unwrap();
+
+        let mut fw = FilesWatcher::new();
+        fw.set_debounce(Duration::from_secs(60));
+        let changes = Arc::new(Mutex::new(vec![]));
+        let record = RecordAction {
+            changes: changes.clone(),
+        };
+        let options = WatchOptions {
+            run_on_start: true,
+            ..Default::default()
+        };
+        fw.add_watch(vec![dir.clone()], options, vec![Box::new(record)])
+            .unwrap();
+        fw.add_watch(vec![dir.join("other")], WatchOptions::default(), Vec::new())
+            .unwrap();
+        fw.queue_start_runs();
+
+        let execution_result = fw.wait_and_execute().unwrap();
+        assert_eq!(1, execution_result.num_actions);
+        let changes = changes.lock().unwrap();
+        assert_eq!(actions::START_KIND, changes[0].kind);
+        assert_eq!(vec![dir.clone()], changes[0].paths);
+        assert_eq!(dir, changes[0].watch_root);
+        assert!(fw.pending.is_empty());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_relative_path() {
+        // Tests run in the crate directory, so a directory in the target
+        // directory can be given relative to it.
+        let name = temp_path().file_name().unwrap().to_owned();
+        let relative = Path::new(".").join("target").join(name);
+        create_dir(&relative).unwrap();
+        let dir = std::path::absolute(&relative).unwrap();
+
+        let mut fw = FilesWatcher::new();
+        let watch_id = fw
+            .add_watch(vec![relative.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+        assert_eq!(vec![dir.clone()], fw.paths_of(watch_id));
+
+        let event =
+            Event::new(EventKind::Create(event::CreateKind::File)).add_path(dir.join("main.rs"));
+        assert!(fw.add_pending(event));
+        let change = fw.pending.remove(&watch_id).unwrap().into_change();
+        assert_eq!(vec![dir.join("main.rs")], change.paths);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignore_close_write_with_default_filter() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        fw.add_watch(vec![dir.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+
+        let close_write = EventKind::Access(event::AccessKind::Close(event::AccessMode::Write));
+        let event = Event::new(close_write).add_path(dir.join("main.rs"));
+        assert!(!fw.add_pending(event));
+        // Not even paths outside of the watch are reported.
+        let event = Event::new(close_write).add_path(temp_path());
+        assert!(!fw.add_pending(event));
+        assert!(fw.pending.is_empty());
+
+        let options = WatchOptions {
+            events: EventFilter::new(&["access_close_write".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        let subdir = dir.join("subdir");
+        create_dir(&subdir).unwrap();
+        let watch_id = fw
+            .add_watch(vec![subdir.clone()], options, Vec::new())
+            .unwrap();
+        let event = Event::new(close_write).add_path(subdir.join("main.rs"));
+        assert!(fw.add_pending(event));
+        assert!(fw.pending.contains_key(&watch_id));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn handle_single_change_of_relative_path() {
+        let name = temp_path().file_name().unwrap().to_owned();
+        let relative = Path::new("target").join(name);
+        create_dir(&relative).unwrap();
+        let dir = std::path::absolute(&relative).unwrap();
+
+        let mut fw = FilesWatcher::new();
+        fw.set_single_change(true);
+        fw.set_workers(1);
+        let changes = Arc::new(Mutex::new(vec![]));
+        let record = RecordAction {
+            changes: changes.clone(),
+        };
+        let path = relative.join("done.txt");
+        fw.add_file(path, vec![Box::new(record)]).unwrap();
+
+        // Creating and writing the file causes several events, but only the
+        // first change is handled.
+        let mut file = File::create(dir.join("done.txt")).unwrap();
+        file.write_all(b"done").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let execution_result = fw.wait_and_execute_until(deadline).unwrap();
+            assert!(!execution_result.timed_out);
+            if execution_result.watch_id.is_some() {
+                assert_eq!(vec![dir.join("done.txt")], execution_result.paths);
+                break;
+            }
+        }
+        sleep(Duration::from_millis(100));
+        assert_eq!(1, changes.lock().unwrap().len());
+        assert!(fw.shutdown(Duration::from_secs(1)));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pair_rename_events() {
+        let dir = create_temp_dir();
+        let old = dir.join("old.rs");
+        let new = dir.join("new.rs");
+
+        let mut fw = FilesWatcher::new();
+        let watch_id = fw
+            .add_watch(vec![dir.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+        let rename = |mode| EventKind::Modify(ModifyKind::Name(mode));
+        fw.add_pending(
+            Event::new(rename(RenameMode::From))
+                .add_path(old.clone())
+                .set_tracker(7),
+        );
+        fw.add_pending(
+            Event::new(rename(RenameMode::To))
+                .add_path(new.clone())
+                .set_tracker(7),
+        );
+        fw.add_pending(
+            Event::new(rename(RenameMode::Both))
+                .add_path(old.clone())
+                .add_path(new.clone())
+                .set_tracker(7),
+        );
+
+        let change = fw.pending.remove(&watch_id).unwrap().into_change();
+        assert_eq!(rename(RenameMode::Both), change.kind);
+        assert_eq!(vec![old.clone(), new.clone()], change.paths);
+        assert_eq!(vec![(old, new)], change.renames);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_file_that_does_not_exist_yet() {
+        let dir = create_temp_dir();
+        let path = dir.join("later").join("main.rs");
+
+        let mut fw = FilesWatcher::new();
+        let changes = Arc::new(Mutex::new(vec![]));
+        let record = RecordAction {
+            changes: changes.clone(),
+        };
+        fw.add_file(path.clone(), vec![Box::new(record)]).unwrap();
+        assert_eq!(Some(&dir), fw.missing.get(&path));
+
+        // Changes to other files in the watched ancestors don't count.
+        File::create(dir.join("other.rs")).unwrap();
+        create_dir(dir.join("later")).unwrap();
+        File::create(&path).unwrap();
+        {
+            let execution_result = wait_for_change(&mut fw);
+            assert_eq!(1, execution_result.num_actions);
+
+            let changes = changes.lock().unwrap();
+            assert!(changes[0].kind.is_create());
+            assert_eq!(vec![path.clone()], changes[0].paths);
+        }
+        assert!(fw.missing.is_empty());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_file_again_after_it_was_removed() {
+        let dir = create_temp_dir();
+        let path = dir.join("main.rs");
+        File::create(&path).unwrap();
+
+        let mut fw = FilesWatcher::new();
+        let changes = Arc::new(Mutex::new(vec![]));
+        let record = RecordAction {
+            changes: changes.clone(),
+        };
+        fw.add_file(path.clone(), vec![Box::new(record)]).unwrap();
+
+        remove_file(&path).unwrap();
+        while !changes
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|change| change.kind.is_remove())
+        {
+            wait_for_change(&mut fw);
+        }
+        assert_eq!(Some(&dir), fw.missing.get(&path));
+
+        File::create(&path).unwrap();
+        while !changes
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|change| change.kind.is_create())
+        {
+            wait_for_change(&mut fw);
+        }
+        assert!(fw.missing.is_empty());
+
+        // The new file is watched itself.
+        changes.lock().unwrap().clear();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"fn main() {}").unwrap();
+        wait_for_change(&mut fw);
+        assert!(changes.lock().unwrap()[0].kind.is_modify());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn follow_missing_path_with_events_filter() {
+        let dir = create_temp_dir();
+        let path = dir.join("main.rs");
+
+        let mut fw = FilesWatcher::new();
+        let options = WatchOptions {
+            events: EventFilter::new(&["modify_data".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        fw.add_watch(vec![path.clone()], options, Vec::new())
+            .unwrap();
+        assert_eq!(Some(&dir), fw.missing.get(&path));
+
+        // The filter doesn't accept the events, but they still move the
+        // watch between the path and its directory.
+        File::create(&path).unwrap();
+        let event = Event::new(EventKind::Create(event::CreateKind::File)).add_path(path.clone());
+        assert!(!fw.add_pending(event));
+        assert!(fw.missing.is_empty());
+
+        remove_file(&path).unwrap();
+        let event = Event::new(EventKind::Remove(event::RemoveKind::File)).add_path(path.clone());
+        assert!(!fw.add_pending(event));
+        assert_eq!(Some(&dir), fw.missing.get(&path));
+        assert!(fw.pending.is_empty());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_options_and_actions_of_watch() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        let include = vec!["*.rs".to_string()];
+        let options = WatchOptions {
+            filter: PathFilter::new(&include, &[]).unwrap(),
+            ..Default::default()
+        };
+        let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(PrintAction::new())];
+        let watch_id = fw.add_watch(vec![dir.clone()], options, actions).unwrap();
+
+        let include = vec!["*.txt".to_string()];
+        let options = WatchOptions {
+            filter: PathFilter::new(&include, &[]).unwrap(),
+            ..Default::default()
+        };
+        fw.set_watch_options(watch_id, options).unwrap();
+        let changes = Arc::new(Mutex::new(vec![]));
+        let record = RecordAction {
+            changes: changes.clone(),
+        };
+        fw.set_actions(watch_id, vec![Box::new(record)]).unwrap();
+
+        File::create(dir.join("main.rs")).unwrap();
+        File::create(dir.join("notes.txt")).unwrap();
+        {
+            let execution_result = wait_for_change(&mut fw);
+            assert_eq!(1, execution_result.num_actions);
+            assert_eq!(Some(watch_id), execution_result.watch_id);
+
+            let changes = changes.lock().unwrap();
+            assert_eq!(vec![dir.join("notes.txt")], changes[0].paths);
+        }
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_same_path_twice() {
+        let dir = create_temp_dir();
+
+        let mut fw = FilesWatcher::new();
+        fw.add_watch(vec![dir.clone()], WatchOptions::default(), Vec::new())
+            .unwrap();
+        let result = fw.add_watch(vec![dir.join(".")], WatchOptions::default(), Vec::new());
+        assert!(matches!(result, Err(Error::AlreadyWatched { .. })));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn actions_run_in_the_order_watches_were_added() {
+        let (path1, _file1) = create_temp_file();
+        let (path2, _file2) = create_temp_file();
+
+        let mut fw = FilesWatcher::new();
+        let changes = Arc::new(Mutex::new(vec![]));
+        for path in [&path1, &path2] {
+            let record = RecordAction {
+                changes: changes.clone(),
+            };
+            let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(record)];
+            fw.add_file(path.clone(), actions).unwrap();
+        }
+
+        // A single event that changes both files, listing the file of the
+        // second watch first.
+        let event = Event::new(EventKind::Modify(event::ModifyKind::Any))
+            .add_path(path2.clone())
+            .add_path(path1.clone());
+        fw.add_pending(event);
+        fw.wait_and_execute().unwrap();
+        fw.wait_and_execute().unwrap();
+
+        {
+            let changes = changes.lock().unwrap();
+            assert_eq!(vec![path1.clone()], changes[0].paths);
+            assert_eq!(vec![path2.clone()], changes[1].paths);
+        }
+
+        remove_temp_file(&path1);
+        remove_temp_file(&path2);
+    }
+
+    #[test]
+    fn actions_run_on_worker_pool() {
+        let (path, _file) = create_temp_file();
+
+        let mut fw = FilesWatcher::new();
+        fw.set_workers(2);
+        let changes = Arc::new(Mutex::new(vec![]));
+        let record = RecordAction {
+            changes: changes.clone(),
+        };
+        let actions: Vec<Box<dyn Action + 'static>> = vec![Box::new(record)];
+        fw.add_file(path.clone(), actions).unwrap();
+
+        let event = Event::new(EventKind::Modify(event::ModifyKind::Any)).add_path(path.clone());
+        fw.add_pending(event);
+        {
+            let execution_result = fw.wait_and_execute().unwrap();
+            assert_eq!(1, execution_result.num_actions);
+            assert_eq!(vec![path.clone()], changes.lock().unwrap()[0].paths);
+        }
+
+        remove_temp_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_timed_out_command() {
+        let (path, _file) = create_temp_file();
+
+        let mut fw = FilesWatcher::new();
+        let mut command = CommandAction::new("sleep 30".to_string(), true).unwrap();
+        command.set_timeout(Duration::from_millis(100));
+        let actions: Vec<Box<dyn Action + 'static>> =
+            vec![Box::new(command), Box::new(PrintAction::new())];
+        fw.add_file(path.clone(), actions).unwrap();
+
+        let event = Event::new(EventKind::Modify(event::ModifyKind::Any)).add_path(path.clone());
+        fw.add_pending(event);
+        {
+            let execution_result = fw.wait_and_execute().unwrap();
+            assert_eq!(1, execution_result.num_actions);
+            assert_eq!(1, execution_result.num_timed_out);
+        }
+
+        remove_temp_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_failed_command() {
+        let (path, _file) = create_temp_file();
+
+        let mut fw = FilesWatcher::new();
+        let command = CommandAction::new("false".to_string(), true).unwrap();
+        let actions: Vec<Box<dyn Action + 'static>> =
+            vec![Box::new(command), Box::new(PrintAction::new())];
+        let options = WatchOptions {
+            on_failure: FailurePolicy::Exit,
+            ..Default::default()
+        };
+        fw.add_watch(vec![path.clone()], options, actions).unwrap();
+
+        let event = Event::new(EventKind::Modify(event::ModifyKind::Any)).add_path(path.clone());
+        fw.add_pending(event);
+        {
+            let execution_result = fw.wait_and_execute().unwrap();
+            assert_eq!(0, execution_result.num_actions);
+            assert_eq!(1, execution_result.failures().count());
+            let outcome = &execution_result.outcomes[0];
+            assert!(matches!(
+                outcome.error,
+                Some(Error::ActionExitStatus { .. })
+            ));
+            assert_eq!(
+                Some(1),
+                outcome.exit_status.and_then(|status| status.code())
+            );
+            assert_eq!(Some(1), execution_result.exit_code);
+        }
+
+        remove_temp_file(&path);
+    }
+
+    #[test]
+    fn pending_changes_of_one_kind() {
+        let kind = EventKind::Modify(event::ModifyKind::Any);
+        let now = Instant::now();
+        let mut changes = PendingChanges::new(kind, PathBuf::from("/"), now);
+        changes.add(kind, PathBuf::from("/a"), now);
+        changes.add(kind, PathBuf::from("/a"), now);
+
+        let change = changes.into_change();
+        assert_eq!(kind, change.kind);
+        assert_eq!(vec![PathBuf::from("/a")], change.paths);
+    }
+
+    #[test]
+    fn pending_changes_of_mixed_kinds() {
+        let create = EventKind::Create(event::CreateKind::File);
+        let now = Instant::now();
+        let mut changes = PendingChanges::new(create, PathBuf::from("/"), now);
+        changes.add(create, PathBuf::from("/a"), now);
+        let modify = EventKind::Modify(event::ModifyKind::Any);
+        changes.add(modify, PathBuf::from("/b"), now);
+
+        let change = changes.into_change();
+        assert_eq!(EventKind::Any, change.kind);
+        assert_eq!(vec![PathBuf::from("/a"), PathBuf::from("/b")], change.paths);
+    }
+
+    #[test]
+    fn relative_path_in_directory() {
+        let root = Path::new("/tmp/project");
+        assert_eq!(
+            PathBuf::from("src/main.rs"),
+            relative_path(root, Path::new("/tmp/project/src/main.rs"))
+        );
+    }
+
+    #[test]
+    fn relative_path_of_watched_file() {
+        let root = Path::new("/tmp/project/main.rs");
+        assert_eq!(PathBuf::from("main.rs"), relative_path(root, root));
+    }
+
     #[test]
     fn is_file_changed_event_read_access() {
         assert!(!is_file_changed_event(&Event::new(EventKind::Access(
@@ -299,7 +1974,31 @@ mod test {
         ))));
     }
 
-    fn create_temp_file() -> (PathBuf, File) {
+    /// Records the changes it handles, so tests can check what the actions
+    /// were called with.
+    struct RecordAction {
+        changes: Arc<Mutex<Vec<Change>>>,
+    }
+
+    impl Action for RecordAction {
+        fn handle_change(&self, change: &Change) -> Result<()> {
+            self.changes.lock().unwrap().push(change.clone());
+            Ok(())
+        }
+    }
+
+    /// Waits for the next event that changes a file, skipping events like
+    /// opening a file or directory.
+    fn wait_for_change(fw: &mut FilesWatcher) -> EventExecutionResult {
+        loop {
+            let execution_result = fw.wait_and_execute().unwrap();
+            if execution_result.was_file_changed {
+                return execution_result;
+            }
+        }
+    }
+
+    fn temp_path() -> PathBuf {
         let rand_part: String = thread_rng()
             .sample_iter(&Alphanumeric)
             .take(8)
@@ -307,7 +2006,19 @@ mod test {
             .collect();
 
         let filename = "eagleeye-test-".to_string() + &rand_part;
-        let path = temp_dir().join(filename);
+        temp_dir().join(filename)
+    }
+
+    fn create_temp_dir() -> PathBuf {
+        let path = temp_path();
+        create_dir(&path)
+            .unwrap_or_else(|error| panic!("Failed to create temporary directory: {}", error));
+
+        path
+    }
+
+    fn create_temp_file() -> (PathBuf, File) {
+        let path = temp_path();
         // let file = File::create(&path)
         //     .unwrap_or_else(|error| panic!("Failed to create temporary file: {}", error));
 
@@ -0,0 +1,308 @@
+extern crate globset;
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use notify::event::{AccessKind, AccessMode, ModifyKind};
+use notify::EventKind;
+use std::path::Path;
+
+/// Decides which changed paths are passed on to a watcher's actions, based
+/// on lists of include and exclude glob patterns.
+///
+/// Patterns are matched against the path relative to the watched path. A
+/// pattern that contains a `/` must match the whole relative path, so
+/// `src/**/*.ts` only matches TypeScript files below `src`. A pattern
+/// without a `/` is matched against the file name alone, so `*.swp` matches
+/// swap files in any directory. `*` never matches a `/`, while `**` matches
+/// any number of directories.
+///
+/// A path passes the filter if it matches at least one include pattern, or
+/// if there are no include patterns at all, and it matches none of the
+/// exclude patterns. Excludes always win over includes.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    include: Option<Patterns>,
+    exclude: Option<Patterns>,
+}
+
+#[derive(Clone, Debug)]
+struct Patterns {
+    paths: GlobSet,
+    names: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter, globset::Error> {
+        Ok(PathFilter {
+            include: Patterns::build(include)?,
+            exclude: Patterns::build(exclude)?,
+        })
+    }
+
+    /// Returns true if the actions should run for a change to `relative_path`,
+    /// the path of the changed file relative to the watched path.
+    pub fn is_match(&self, relative_path: &Path) -> bool {
+        let included = match &self.include {
+            Some(include) => include.is_match(relative_path),
+            None => true,
+        };
+        let excluded = match &self.exclude {
+            Some(exclude) => exclude.is_match(relative_path),
+            None => false,
+        };
+
+        included && !excluded
+    }
+}
+
+impl Patterns {
+    fn build(patterns: &[String]) -> Result<Option<Patterns>, globset::Error> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+
+        let mut paths = GlobSetBuilder::new();
+        let mut names = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = build_glob(pattern)?;
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+
+        Ok(Some(Patterns {
+            paths: paths.build()?,
+            names: names.build()?,
+        }))
+    }
+
+    fn is_match(&self, relative_path: &Path) -> bool {
+        if self.paths.is_match(relative_path) {
+            return true;
+        }
+
+        match relative_path.file_name() {
+            Some(name) => self.names.is_match(name),
+            None => false,
+        }
+    }
+}
+
+fn build_glob(pattern: &str) -> Result<Glob, globset::Error> {
+    // A leading slash anchors the pattern to the watched path, which is the
+    // same as not having one since all matching is relative.
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+
+    GlobBuilder::new(pattern).literal_separator(true).build()
+}
+
+/// A kind of file event that a watcher's actions can be limited to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventType {
+    Create,
+    Remove,
+    Rename,
+    /// The contents of a file changed. Modifications that the platform
+    /// doesn't report details for count as this.
+    ModifyData,
+    /// The permissions, timestamps or other metadata of a file changed.
+    ModifyMetadata,
+    /// A file that was open for writing was closed. Not all platforms
+    /// report this.
+    AccessCloseWrite,
+}
+
+/// The names of the event types, in the order they are listed in messages.
+pub const EVENT_TYPE_NAMES: &str =
+    "create, remove, rename, modify_data, modify_metadata or access_close_write";
+
+impl EventType {
+    /// Returns the event type with a name like "modify_data", or `None` if
+    /// the name isn't known.
+    pub fn from_name(name: &str) -> Option<EventType> {
+        match name {
+            "create" => Some(EventType::Create),
+            "remove" => Some(EventType::Remove),
+            "rename" => Some(EventType::Rename),
+            "modify_data" => Some(EventType::ModifyData),
+            "modify_metadata" => Some(EventType::ModifyMetadata),
+            "access_close_write" => Some(EventType::AccessCloseWrite),
+            _ => None,
+        }
+    }
+
+    /// Returns the event type of an event kind reported by notify, or
+    /// `None` for kinds that no watcher is interested in, like reading a
+    /// file.
+    pub fn of(kind: &EventKind) -> Option<EventType> {
+        match kind {
+            EventKind::Create(_) => Some(EventType::Create),
+            EventKind::Remove(_) => Some(EventType::Remove),
+            EventKind::Modify(ModifyKind::Name(_)) => Some(EventType::Rename),
+            EventKind::Modify(ModifyKind::Metadata(_)) => Some(EventType::ModifyMetadata),
+            EventKind::Modify(_) => Some(EventType::ModifyData),
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                Some(EventType::AccessCloseWrite)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Decides which kinds of file events are passed on to a watcher's actions.
+/// By default, all changes to files are, but closing a file is not, as
+/// writing to it is already a change.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventFilter {
+    types: Vec<EventType>,
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        EventFilter {
+            types: vec![
+                EventType::Create,
+                EventType::Remove,
+                EventType::Rename,
+                EventType::ModifyData,
+                EventType::ModifyMetadata,
+            ],
+        }
+    }
+}
+
+impl EventFilter {
+    /// Creates a filter that passes the events with the given type names.
+    /// Without any names, the default filter is returned. Returns the first
+    /// unknown name as the error.
+    pub fn new(names: &[String]) -> Result<EventFilter, String> {
+        if names.is_empty() {
+            return Ok(EventFilter::default());
+        }
+
+        let types = names
+            .iter()
+            .map(|name| EventType::from_name(name).ok_or_else(|| name.clone()))
+            .collect::<Result<Vec<EventType>, String>>()?;
+        Ok(EventFilter { types })
+    }
+
+    /// Returns true if the actions should run for an event of this kind.
+    pub fn is_match(&self, kind: &EventKind) -> bool {
+        match EventType::of(kind) {
+            Some(event_type) => self.types.contains(&event_type),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, MetadataKind, RemoveKind, RenameMode};
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        let include: Vec<String> = include.iter().map(|p| p.to_string()).collect();
+        let exclude: Vec<String> = exclude.iter().map(|p| p.to_string()).collect();
+        PathFilter::new(&include, &exclude).unwrap()
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = PathFilter::default();
+        assert!(filter.is_match(Path::new("main.rs")));
+        assert!(filter.is_match(Path::new("target/debug/eagle")));
+    }
+
+    #[test]
+    fn include_double_star_matches_any_depth() {
+        let filter = filter(&["**/*.rs"], &[]);
+        assert!(filter.is_match(Path::new("main.rs")));
+        assert!(filter.is_match(Path::new("src/actions/print.rs")));
+        assert!(!filter.is_match(Path::new("Cargo.toml")));
+    }
+
+    #[test]
+    fn include_path_pattern_is_anchored() {
+        let filter = filter(&["src/**/*.ts"], &[]);
+        assert!(filter.is_match(Path::new("src/index.ts")));
+        assert!(filter.is_match(Path::new("src/lib/util.ts")));
+        assert!(!filter.is_match(Path::new("test/src/index.ts")));
+        assert!(!filter.is_match(Path::new("src/index.js")));
+    }
+
+    #[test]
+    fn single_star_does_not_cross_directories() {
+        let filter = filter(&["src/*.rs"], &[]);
+        assert!(filter.is_match(Path::new("src/main.rs")));
+        assert!(!filter.is_match(Path::new("src/actions/print.rs")));
+    }
+
+    #[test]
+    fn name_pattern_matches_in_any_directory() {
+        let filter = filter(&[], &["*.swp", "*~"]);
+        assert!(!filter.is_match(Path::new(".main.rs.swp")));
+        assert!(!filter.is_match(Path::new("src/.main.rs.swp")));
+        assert!(!filter.is_match(Path::new("src/main.rs~")));
+        assert!(filter.is_match(Path::new("src/main.rs")));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let filter = filter(&["**/*.rs"], &["target/**"]);
+        assert!(filter.is_match(Path::new("src/main.rs")));
+        assert!(!filter.is_match(Path::new("target/debug/build/out.rs")));
+    }
+
+    #[test]
+    fn leading_slash_is_relative_to_watched_path() {
+        let filter = filter(&[], &["/target/**"]);
+        assert!(!filter.is_match(Path::new("target/debug/eagle")));
+        assert!(filter.is_match(Path::new("src/target/mod.rs")));
+    }
+
+    #[test]
+    fn default_event_filter_matches_changes() {
+        let filter = EventFilter::default();
+        assert!(filter.is_match(&EventKind::Create(CreateKind::File)));
+        assert!(filter.is_match(&EventKind::Modify(ModifyKind::Any)));
+        assert!(filter.is_match(&EventKind::Modify(ModifyKind::Name(RenameMode::Both))));
+        assert!(filter.is_match(&EventKind::Remove(RemoveKind::Any)));
+        assert!(!filter.is_match(&EventKind::Access(AccessKind::Read)));
+        assert!(!filter.is_match(&EventKind::Access(AccessKind::Close(AccessMode::Write))));
+    }
+
+    #[test]
+    fn event_filter_matches_only_given_types() {
+        let names = vec!["access_close_write".to_string(), "rename".to_string()];
+        let filter = EventFilter::new(&names).unwrap();
+        assert!(filter.is_match(&EventKind::Access(AccessKind::Close(AccessMode::Write))));
+        assert!(filter.is_match(&EventKind::Modify(ModifyKind::Name(RenameMode::From))));
+        assert!(!filter.is_match(&EventKind::Access(AccessKind::Close(AccessMode::Read))));
+        assert!(!filter.is_match(&EventKind::Modify(ModifyKind::Data(DataChange::Content))));
+        assert!(!filter.is_match(&EventKind::Create(CreateKind::File)));
+    }
+
+    #[test]
+    fn metadata_changes_are_not_data_changes() {
+        let names = vec!["modify_data".to_string()];
+        let filter = EventFilter::new(&names).unwrap();
+        assert!(filter.is_match(&EventKind::Modify(ModifyKind::Data(DataChange::Any))));
+        assert!(!filter.is_match(&EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::Permissions
+        ))));
+    }
+
+    #[test]
+    fn unknown_event_type() {
+        let names = vec!["create".to_string(), "write".to_string()];
+        assert_eq!(Err("write".to_string()), EventFilter::new(&names));
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let include = vec!["src/[".to_string()];
+        assert!(PathFilter::new(&include, &[]).is_err());
+    }
+}
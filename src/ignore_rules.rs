@@ -0,0 +1,272 @@
+extern crate ignore;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// Decides which paths below a watched directory are ignored by git, using
+/// the `.gitignore` and `.ignore` files in the directory tree and in its
+/// parents up to the root of the repository it's in, the repository's
+/// `.git/info/exclude` file and the global git excludes file. The `.git`
+/// directory itself is always ignored.
+///
+/// Ignore files are read the first time a path in their directory is
+/// checked, and read again after `reload_if_ignore_file` is called for them.
+pub struct IgnoreRules {
+    base: PathBuf,
+    /// The root of the repository containing `base`, or `base` itself if
+    /// it's not in a repository.
+    repository: PathBuf,
+    global: Gitignore,
+    directories: RefCell<HashMap<PathBuf, Gitignore>>,
+}
+
+impl IgnoreRules {
+    /// Creates the ignore rules for a watched path. If `root` is a file, the
+    /// ignore files in the directory containing it are used.
+    pub fn new(root: &Path) -> IgnoreRules {
+        let base = if root.is_dir() {
+            root.to_path_buf()
+        } else {
+            root.parent().map(Path::to_path_buf).unwrap_or_default()
+        };
+
+        let repository = repository_root(&base).unwrap_or_else(|| base.clone());
+        let (global, error) = GitignoreBuilder::new(&repository).build_global();
+        if let Some(error) = error {
+            println!("Warning: could not read global git excludes: {}", error);
+        }
+
+        IgnoreRules {
+            base,
+            repository,
+            global,
+            directories: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if `path` is ignored. Rules in deeper directories take
+    /// precedence over rules closer to the repository root, which take
+    /// precedence over the global excludes.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        if !path.starts_with(&self.base) {
+            return false;
+        }
+        let relative = path.strip_prefix(&self.repository).unwrap_or(path);
+
+        if relative.components().any(|c| c.as_os_str() == ".git") {
+            return true;
+        }
+
+        let is_dir = path.is_dir();
+        let mut directory = path.parent();
+        while let Some(dir) = directory {
+            if !dir.starts_with(&self.repository) {
+                break;
+            }
+
+            let rules = self.rules_for(dir);
+            let dir_relative = path.strip_prefix(dir).unwrap_or(path);
+            match rules.matched_path_or_any_parents(dir_relative, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+
+            directory = dir.parent();
+        }
+
+        self.global
+            .matched_path_or_any_parents(relative, is_dir)
+            .is_ignore()
+    }
+
+    /// Forgets the cached rules for a directory if `path` is one of the
+    /// ignore files, so they are read again on the next check. Returns true
+    /// if the path was an ignore file.
+    pub fn reload_if_ignore_file(&self, path: &Path) -> bool {
+        let dir = match ignore_file_directory(&self.repository, path) {
+            Some(dir) => dir,
+            None => return false,
+        };
+
+        self.directories.borrow_mut().remove(&dir);
+        true
+    }
+
+    fn rules_for(&self, dir: &Path) -> Gitignore {
+        if let Some(rules) = self.directories.borrow().get(dir) {
+            return rules.clone();
+        }
+
+        let mut builder = GitignoreBuilder::new(dir);
+        if dir == self.repository {
+            add_ignore_file(&mut builder, &dir.join(".git").join("info").join("exclude"));
+        }
+        // Later files take precedence, so .ignore wins over .gitignore.
+        for name in IGNORE_FILE_NAMES {
+            add_ignore_file(&mut builder, &dir.join(name));
+        }
+
+        let rules = builder.build().unwrap_or_else(|error| {
+            println!(
+                "Warning: could not read ignore files in {:?}: {}",
+                dir, error
+            );
+            Gitignore::empty()
+        });
+
+        self.directories
+            .borrow_mut()
+            .insert(dir.to_path_buf(), rules.clone());
+
+        rules
+    }
+}
+
+/// Returns the closest directory containing `dir` that is the root of a git
+/// repository, including `dir` itself.
+fn repository_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|ancestor| ancestor.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+/// Returns the directory whose rules are defined by `path`, if `path` is a
+/// `.gitignore` or `.ignore` file or the exclude file of the repository at
+/// `repository`.
+fn ignore_file_directory(repository: &Path, path: &Path) -> Option<PathBuf> {
+    if path == repository.join(".git").join("info").join("exclude") {
+        return Some(repository.to_path_buf());
+    }
+
+    let name = path.file_name()?;
+    if IGNORE_FILE_NAMES
+        .iter()
+        .any(|ignore_name| name == *ignore_name)
+    {
+        return path.parent().map(Path::to_path_buf);
+    }
+
+    None
+}
+
+fn add_ignore_file(builder: &mut GitignoreBuilder, path: &Path) {
+    if !path.is_file() {
+        return;
+    }
+
+    if let Some(error) = builder.add(path) {
+        println!("Warning: could not read ignore file {:?}: {}", path, error);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate rand;
+
+    use super::*;
+
+    use self::rand::distributions::Alphanumeric;
+    use self::rand::{thread_rng, Rng};
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn git_directory_is_ignored() {
+        let dir = create_temp_dir();
+        let rules = IgnoreRules::new(&dir);
+
+        assert!(rules.is_ignored(&dir.join(".git").join("index")));
+        assert!(!rules.is_ignored(&dir.join("main.rs")));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gitignore_in_root() {
+        let dir = create_temp_dir();
+        write(dir.join(".gitignore"), "target/\n*.swp\n").unwrap();
+        create_dir_all(dir.join("target").join("debug")).unwrap();
+        let rules = IgnoreRules::new(&dir);
+
+        assert!(rules.is_ignored(&dir.join("target").join("debug").join("eagle")));
+        assert!(rules.is_ignored(&dir.join("src").join(".main.rs.swp")));
+        assert!(!rules.is_ignored(&dir.join("src").join("main.rs")));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_ignore_files_take_precedence() {
+        let dir = create_temp_dir();
+        create_dir_all(dir.join("docs")).unwrap();
+        write(dir.join(".gitignore"), "*.html\n").unwrap();
+        write(dir.join("docs").join(".ignore"), "!index.html\n*.md\n").unwrap();
+        let rules = IgnoreRules::new(&dir);
+
+        assert!(rules.is_ignored(&dir.join("page.html")));
+        assert!(!rules.is_ignored(&dir.join("docs").join("index.html")));
+        assert!(rules.is_ignored(&dir.join("docs").join("README.md")));
+        assert!(!rules.is_ignored(&dir.join("README.md")));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rules_of_repository_apply_to_subdirectory() {
+        let dir = create_temp_dir();
+        let src = dir.join("src");
+        create_dir_all(dir.join(".git").join("info")).unwrap();
+        create_dir_all(&src).unwrap();
+        write(dir.join(".git").join("info").join("exclude"), "*.tmp\n").unwrap();
+        write(dir.join(".gitignore"), "/src/generated/\n*.log\n").unwrap();
+        let rules = IgnoreRules::new(&src);
+
+        assert!(rules.is_ignored(&src.join("build.tmp")));
+        assert!(rules.is_ignored(&src.join("build.log")));
+        assert!(rules.is_ignored(&src.join("generated").join("parser.rs")));
+        assert!(!rules.is_ignored(&src.join("main.rs")));
+        // Paths outside of the watched directory are never ignored.
+        assert!(!rules.is_ignored(&dir.join("build.log")));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reload_changed_ignore_file() {
+        let dir = create_temp_dir();
+        let gitignore = dir.join(".gitignore");
+        write(&gitignore, "*.log\n").unwrap();
+        let rules = IgnoreRules::new(&dir);
+
+        assert!(rules.is_ignored(&dir.join("build.log")));
+
+        write(&gitignore, "*.tmp\n").unwrap();
+        assert!(rules.reload_if_ignore_file(&gitignore));
+        assert!(!rules.is_ignored(&dir.join("build.log")));
+        assert!(rules.is_ignored(&dir.join("build.tmp")));
+
+        assert!(!rules.reload_if_ignore_file(&dir.join("build.tmp")));
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    fn create_temp_dir() -> PathBuf {
+        let rand_part: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+
+        let path = temp_dir().join("eagleeye-test-".to_string() + &rand_part);
+        create_dir_all(&path)
+            .unwrap_or_else(|error| panic!("Failed to create temporary directory: {}", error));
+
+        path
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+
+/// A problem with a `${...}` reference found while interpolating a string.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InterpolateError {
+    /// The variable is not set and the reference has no default.
+    Undefined(String),
+    /// The text between `${` and `}` is not a valid variable name.
+    InvalidName(String),
+    /// A `${` is never closed by a `}`.
+    Unclosed,
+}
+
+impl fmt::Display for InterpolateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpolateError::Undefined(name) => write!(f, "undefined variable {}", name),
+            InterpolateError::InvalidName(name) => write!(f, "invalid variable name {:?}", name),
+            InterpolateError::Unclosed => write!(f, "unclosed ${{"),
+        }
+    }
+}
+
+/// Replaces references to variables in `text` with their values, as looked
+/// up by `lookup`.
+///
+/// `${NAME}` is replaced with the value of `NAME`, which must be set.
+/// `${NAME:-default}` is replaced with `default` if `NAME` is not set or
+/// empty. `$${` stands for a literal `${`, and any other `$` is kept as it
+/// is, so `$HOME` is left for a shell to expand. References to `EAGLE_`
+/// variables are kept too, because eagle only sets them when it runs a
+/// command for a change. All problems in the text are returned, not just
+/// the first one.
+pub fn interpolate<F>(text: &str, lookup: F) -> Result<String, Vec<InterpolateError>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(text.len());
+    let mut errors = vec![];
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start..];
+
+        if let Some(escaped) = after.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some(reference) = after.strip_prefix("${") else {
+            result.push('$');
+            rest = &after[1..];
+            continue;
+        };
+        let Some(end) = reference.find('}') else {
+            errors.push(InterpolateError::Unclosed);
+            break;
+        };
+
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        if !is_valid_name(name) {
+            errors.push(InterpolateError::InvalidName(name.to_string()));
+        } else if name.starts_with("EAGLE_") {
+            result.push_str(&after[..end + 3]);
+        } else {
+            match (lookup(name), default) {
+                (Some(value), Some(default)) if value.is_empty() => result.push_str(default),
+                (Some(value), _) => result.push_str(&value),
+                (None, Some(default)) => result.push_str(default),
+                (None, None) => errors.push(InterpolateError::Undefined(name.to_string())),
+            }
+        }
+        rest = &reference[end + 1..];
+    }
+    result.push_str(rest);
+
+    if errors.is_empty() {
+        Ok(result)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Returns true if `name` can be the name of an environment variable, like
+/// `HOME` or `build_dir2`.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/eagle".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn replace_variables() {
+        assert_eq!(
+            Ok("/home/eagle/src and /home/eagle".to_string()),
+            interpolate("${HOME}/src and ${HOME}", lookup)
+        );
+    }
+
+    #[test]
+    fn use_default_if_unset_or_empty() {
+        assert_eq!(
+            Ok("/tmp/build:/tmp/empty:/home/eagle".to_string()),
+            interpolate(
+                "${BUILD_DIR:-/tmp/build}:${EMPTY:-/tmp/empty}:${HOME:-/root}",
+                lookup
+            )
+        );
+        assert_eq!(Ok("".to_string()), interpolate("${NOPE:-}", lookup));
+    }
+
+    #[test]
+    fn keep_other_dollars() {
+        assert_eq!(
+            Ok("echo $HOME ${HOME} $ 5$".to_string()),
+            interpolate("echo $HOME $${HOME} $ 5$", lookup)
+        );
+    }
+
+    #[test]
+    fn keep_eagle_variables() {
+        assert_eq!(
+            Ok("echo ${EAGLE_PATH} ${EAGLE_OLD_PATH:-none} /home/eagle".to_string()),
+            interpolate("echo ${EAGLE_PATH} ${EAGLE_OLD_PATH:-none} ${HOME}", lookup)
+        );
+    }
+
+    #[test]
+    fn report_all_problems() {
+        assert_eq!(
+            Err(vec![
+                InterpolateError::Undefined("NOPE".to_string()),
+                InterpolateError::InvalidName("1X".to_string()),
+                InterpolateError::Undefined("ALSO_NOPE".to_string()),
+                InterpolateError::Unclosed,
+            ]),
+            interpolate("${NOPE}/${1X}/${HOME}/${ALSO_NOPE}/${HOME", lookup)
+        );
+    }
+}
@@ -6,119 +6,894 @@ extern crate toml;
 
 pub mod actions;
 pub mod config;
+pub mod error;
 pub mod files_watcher;
+pub mod filter;
+pub mod ignore_rules;
+pub mod interpolate;
+pub mod signals;
+pub mod worker_pool;
 
+use std::collections::BTreeMap;
+use std::env;
 use std::path::PathBuf;
 use std::process;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::actions::command::CommandAction;
+use crate::actions::command::{CommandAction, Shell};
+use crate::actions::log::LogAction;
 use crate::actions::print::PrintAction;
+use crate::actions::restart::{self, RestartAction};
 use crate::actions::Action;
-use crate::config::SettingsConfig;
-use crate::files_watcher::FilesWatcher;
+use crate::config::{ActionSettings, Config, ShellSetting, WatcherDiff, WatcherSettings};
+use crate::error::Result;
+use crate::files_watcher::{FilesWatcher, WatchOptions};
+use crate::filter::{EventFilter, PathFilter, EVENT_TYPE_NAMES};
+use crate::worker_pool::{FailurePolicy, QueuePolicy};
 use clap::Parser;
 
+/// How long to wait for further changes to the config file before reloading
+/// it.
+const CONFIG_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// The exit code when `--once` gives up waiting for a change, the same as
+/// the one of timeout(1).
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     /// Path to a TOML config file. This option is mutually exclusive to the
-    /// -p and -e options.
+    /// -p and -e options. Without any of them, the first .eagle.toml or
+    /// eagle.toml in the working directory or its parents is used, and
+    /// otherwise $XDG_CONFIG_HOME/eagle/config.toml.
     #[arg(short, long, value_name = "FILE")]
     config: Option<PathBuf>,
 
     /// A command to execute whenever a change happens. If the command contains
     /// one or more instances of {:p}, they will be replaced by the path to the
-    /// changed file or folder. Requires also specifying the -p option.
+    /// changed file or folder. See the README for more placeholders.
+    /// Arguments can be quoted like in a shell. Requires also specifying the
+    /// -p option.
     #[arg(short, long)]
     execute: Option<String>,
 
-    /// Path to a file or directory to watch for changes.  Requires also specifying
-    /// the -e option.
+    /// Run the -e command with a shell, so pipes, redirects and && work. The
+    /// shell program can be given as a value, otherwise sh is used (cmd on
+    /// Windows).
+    #[arg(short, long, value_name = "SHELL")]
+    shell: Option<Option<String>>,
+
+    /// Treat the -e command as a long-running process, like a development
+    /// server. It is started right away, and restarted on every change.
+    #[arg(short = 'R', long, default_value = "false")]
+    restart: bool,
+
+    /// The signal that asks the --restart process to exit before it is
+    /// restarted.
+    #[arg(long, value_name = "SIGNAL", default_value = "SIGTERM")]
+    signal: String,
+
+    /// How long to wait for the --restart process or a timed out command to
+    /// exit after sending the signal, before killing it.
+    #[arg(long, value_name = "MS", default_value = "5000")]
+    grace: u64,
+
+    /// Stop the -e command if it runs for longer than this many
+    /// milliseconds. It's sent SIGTERM, and killed after the --grace period.
+    #[arg(long, value_name = "MS")]
+    command_timeout: Option<u64>,
+
+    /// Path to a file or directory to watch for changes. Can be given
+    /// multiple times to watch several paths. Requires also specifying the
+    /// -e option.
     #[arg(short, long, value_name = "PATH")]
-    path: Option<PathBuf>,
+    path: Vec<PathBuf>,
+
+    /// Watch the whole directory tree below the -p path, including
+    /// directories created after eagle was started.
+    #[arg(short, long, default_value = "false")]
+    recursive: bool,
+
+    /// Only react to changed files matching this glob pattern. Patterns
+    /// containing a slash are matched against the path relative to the -p
+    /// path, other patterns against the file name. Can be given multiple
+    /// times.
+    #[arg(short, long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Ignore changed files matching this glob pattern, even if they match
+    /// an --include pattern. Can be given multiple times.
+    #[arg(short = 'x', long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Ignore changes to files that are ignored by git, using .gitignore and
+    /// .ignore files, the repository's exclude file and the global git
+    /// excludes file.
+    #[arg(short, long, default_value = "false")]
+    gitignore: bool,
+
+    /// Only run the actions for these kinds of events: create, remove,
+    /// rename, modify_data, modify_metadata or access_close_write. Can be
+    /// given multiple times or separated by commas. By default, all but
+    /// access_close_write are used.
+    #[arg(long, value_name = "KIND", value_delimiter = ',')]
+    events: Vec<String>,
+
+    /// Wait until no further changes have happened for this many
+    /// milliseconds, then execute the command once for all changed paths.
+    #[arg(short, long, value_name = "MS")]
+    debounce: Option<u64>,
+
+    /// Run actions on this many worker threads, so changes keep being
+    /// noticed while a slow command runs.
+    #[arg(long, value_name = "N", default_value = "4")]
+    workers: usize,
+
+    /// How many times the actions may run at the same time.
+    #[arg(long, value_name = "N", default_value = "1")]
+    max_concurrent: usize,
+
+    /// What to do with a change that happens while the actions are already
+    /// running --max-concurrent times: "queue" runs them again afterwards,
+    /// "drop-new" ignores the change, and "restart" cancels the running
+    /// actions and runs them again.
+    #[arg(long, value_name = "POLICY", default_value = "queue")]
+    on_busy: String,
+
+    /// What to do when an action fails, for example when a command exits
+    /// with a non-zero status: "continue" runs the remaining actions,
+    /// "stop" skips them, and "exit" makes eagle exit with the command's
+    /// exit code.
+    #[arg(long, value_name = "POLICY", default_value = "continue")]
+    on_failure: String,
+
+    /// Wait for a single change, run the actions for it and exit with the
+    /// exit code of the first failed command, or 0. Without -e or a config
+    /// file, the changed paths are printed instead.
+    #[arg(long, default_value = "false")]
+    once: bool,
+
+    /// Run the actions once at startup, before anything changed. In a config
+    /// file, set run_on_start on a watcher instead.
+    #[arg(long, default_value = "false", conflicts_with = "once")]
+    initial_run: bool,
+
+    /// With --once, give up and exit with code 124 if nothing has changed
+    /// after this many milliseconds.
+    #[arg(long, value_name = "MS", requires = "once")]
+    timeout: Option<u64>,
+
+    /// When eagle is asked to exit, wait this many milliseconds for running
+    /// actions to finish before stopping them.
+    #[arg(long, value_name = "MS", default_value = "5000")]
+    shutdown_timeout: u64,
 
     /// Do not print file change information.
     #[arg(short, long, default_value = "false")]
     quiet: bool,
 }
 
+fn to_shell(setting: Option<&ShellSetting>) -> Shell {
+    match setting {
+        None | Some(ShellSetting::Enabled(false)) => Shell::None,
+        Some(ShellSetting::Enabled(true)) => Shell::Default,
+        Some(ShellSetting::Program(program)) => Shell::Program(program.to_owned()),
+    }
+}
+
+/// Settings of the config file that apply to the actions of all watchers.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ActionDefaults {
+    quiet: bool,
+    /// The variables of the `[env]` table.
+    env: BTreeMap<String, String>,
+}
+
+impl ActionDefaults {
+    fn from_config(config: &Config) -> ActionDefaults {
+        let settings = config.settings.as_ref();
+        ActionDefaults {
+            quiet: settings
+                .and_then(|settings| settings.quiet)
+                .unwrap_or(false),
+            env: config.env.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Creates the command of a command or restart action. A command without
+/// its own shell setting uses the watcher's. The environment variables of
+/// the watcher override the ones of the `[env]` table.
+fn build_command(
+    settings: &ActionSettings,
+    watcher: &WatcherSettings,
+    defaults: &ActionDefaults,
+) -> Result<CommandAction> {
+    let execute = settings.execute.clone().unwrap_or_default();
+    let mut command = CommandAction::new(execute, defaults.quiet)?;
+    command.set_shell(to_shell(settings.shell.as_ref().or(watcher.shell.as_ref())));
+    command.validate()?;
+
+    let mut env = defaults.env.clone();
+    env.extend(watcher.env.clone().unwrap_or_default());
+    command.set_env(env);
+    command.set_env_clear(watcher.env_clear.unwrap_or(false));
+    if let Some(cwd) = &watcher.cwd {
+        command.set_cwd(PathBuf::from(cwd));
+    }
+
+    if let Some(timeout_ms) = settings.timeout_ms {
+        command.set_timeout(Duration::from_millis(timeout_ms));
+    }
+    if let Some(grace_ms) = settings.grace_ms {
+        command.set_grace_period(Duration::from_millis(grace_ms));
+    }
+
+    Ok(command)
+}
+
+/// Creates a restart action. Its process is started when it's added to a
+/// watch.
+fn build_restart(
+    command: CommandAction,
+    signal: Option<i32>,
+    grace_ms: Option<u64>,
+    quiet: bool,
+) -> RestartAction {
+    let mut restart = RestartAction::new(command, quiet);
+    if let Some(signal) = signal {
+        restart.set_signal(signal);
+    }
+    if let Some(grace_ms) = grace_ms {
+        restart.set_grace_period(Duration::from_millis(grace_ms));
+    }
+
+    restart
+}
+
+/// Creates the action described by an entry in a watcher's action list.
+/// Unknown action types are skipped, but a validated config has none.
+fn build_action(
+    settings: &ActionSettings,
+    watcher: &WatcherSettings,
+    defaults: &ActionDefaults,
+) -> Result<Option<Box<dyn Action + 'static>>> {
+    let action: Box<dyn Action> = match settings.action_type.as_ref() {
+        "print" => Box::new(PrintAction::new()),
+        "command" => Box::new(build_command(settings, watcher, defaults)?),
+        "restart" => {
+            let command = build_command(settings, watcher, defaults)?;
+            let signal = settings.signal.as_deref().and_then(restart::parse_signal);
+            let grace_ms = settings.grace_ms;
+            Box::new(build_restart(command, signal, grace_ms, defaults.quiet))
+        }
+        "log" => match &settings.file {
+            Some(file) => Box::new(LogAction::new(PathBuf::from(file))),
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some(action))
+}
+
+fn build_actions(
+    watcher: &WatcherSettings,
+    defaults: &ActionDefaults,
+) -> Result<Vec<Box<dyn Action>>> {
+    let mut actions = vec![];
+    for action_settings in watcher.all_actions() {
+        if let Some(action) = build_action(&action_settings, watcher, defaults)? {
+            actions.push(action);
+        }
+    }
+
+    Ok(actions)
+}
+
+/// Creates the options of a watcher. The config was validated, so invalid
+/// globs and policy names don't occur.
+fn build_options(watcher: &WatcherSettings) -> WatchOptions {
+    let include = watcher.include.as_deref().unwrap_or_default();
+    let exclude = watcher.exclude.as_deref().unwrap_or_default();
+    let on_busy = watcher.on_busy.as_deref().and_then(QueuePolicy::from_name);
+    let on_failure = watcher
+        .on_failure
+        .as_deref()
+        .and_then(FailurePolicy::from_name);
+
+    WatchOptions {
+        recursive: watcher.recursive.unwrap_or(false),
+        filter: PathFilter::new(include, exclude).unwrap_or_default(),
+        events: EventFilter::new(watcher.events.as_deref().unwrap_or_default()).unwrap_or_default(),
+        respect_gitignore: watcher.respect_gitignore.unwrap_or(false),
+        debounce: watcher.debounce_ms.map(Duration::from_millis),
+        max_concurrent: watcher.max_concurrent,
+        queue_policy: on_busy.unwrap_or_default(),
+        on_failure: on_failure.unwrap_or_default(),
+        run_on_start: watcher.run_on_start.unwrap_or(false),
+    }
+}
+
+/// Adds a watch for a watcher of the config file and returns its id.
+fn add_watcher(
+    fw: &mut FilesWatcher,
+    watcher: &WatcherSettings,
+    defaults: &ActionDefaults,
+) -> Result<usize> {
+    let paths = watcher.all_paths().into_iter().map(PathBuf::from).collect();
+    let actions = build_actions(watcher, defaults)?;
+    fw.add_watch(paths, build_options(watcher), actions)
+}
+
+/// Applies the changed settings of a watcher to its watch. The actions are
+/// only rebuilt if they or the defaults for them changed, so restarted
+/// processes keep running otherwise. The actions are built before anything
+/// is applied, so a watcher with invalid actions keeps its old options, and
+/// if they can't be started, it keeps its old actions too.
+fn update_watcher(
+    fw: &mut FilesWatcher,
+    watch_id: usize,
+    old: &WatcherSettings,
+    new: &WatcherSettings,
+    defaults: &ActionDefaults,
+    defaults_changed: bool,
+) -> Result<()> {
+    let actions = if defaults_changed || new.has_other_actions(old) {
+        Some(build_actions(new, defaults)?)
+    } else {
+        None
+    };
+
+    fw.set_watch_options(watch_id, build_options(new))?;
+    if let Some(actions) = actions {
+        if let Err(error) = fw.set_actions(watch_id, actions) {
+            let _ = fw.set_watch_options(watch_id, build_options(old));
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+/// A watcher of the config file and the id of its watch.
+struct LoadedWatcher {
+    settings: WatcherSettings,
+    watch_id: usize,
+}
+
+/// The config file eagle was started with, and the watches set up for it.
+/// The file itself is watched too, and changes to it are applied to the
+/// watches while eagle runs.
+struct LoadedConfig {
+    path: PathBuf,
+    defaults: ActionDefaults,
+    workers: Option<usize>,
+    shutdown_timeout_ms: Option<u64>,
+    watchers: Vec<LoadedWatcher>,
+    /// The watch on the config file, if it could be watched.
+    watch_id: Option<usize>,
+}
+
+impl LoadedConfig {
+    /// Watches the config file, replacing an earlier watch on it. Editors
+    /// often save by replacing the file, so it's watched again after every
+    /// change to follow the new file.
+    fn watch(&mut self, fw: &mut FilesWatcher) {
+        if let Some(watch_id) = self.watch_id.take() {
+            fw.remove_watch(watch_id);
+        }
+
+        // File events have absolute paths, so the config file is watched by
+        // its absolute path to match them. Saving a file can cause several
+        // events, which are collected into a single reload.
+        let path = std::path::absolute(&self.path).unwrap_or_else(|_| self.path.clone());
+        let options = WatchOptions {
+            debounce: Some(CONFIG_DEBOUNCE),
+            ..Default::default()
+        };
+        match fw.add_watch(vec![path], options, vec![]) {
+            Ok(watch_id) => self.watch_id = Some(watch_id),
+            Err(error) => println!("Changes to the config file are not applied: {}", error),
+        }
+    }
+
+    /// Reads the changed config file and applies the differences to the
+    /// watches. If the new config is invalid, the old one keeps running.
+    fn reload(&mut self, fw: &mut FilesWatcher) {
+        self.watch(fw);
+
+        let config = match config::parse_file(&self.path) {
+            Ok(config) => config,
+            Err(error) => {
+                println!("Error: {}. Keeping the previous config.", error);
+                return;
+            }
+        };
+
+        let defaults = ActionDefaults::from_config(&config);
+        let defaults_changed = defaults != self.defaults;
+        let settings = config.settings.unwrap_or_default();
+        fw.set_debounce(Duration::from_millis(settings.debounce_ms.unwrap_or(0)));
+        if settings.workers != self.workers {
+            println!("The number of workers is only changed when eagle is restarted.");
+        }
+        if settings.shutdown_timeout_ms != self.shutdown_timeout_ms {
+            println!("The shutdown timeout is only changed when eagle is restarted.");
+        }
+
+        let new_watchers = config.watchers.unwrap_or_default();
+        let old_settings: Vec<WatcherSettings> = self
+            .watchers
+            .iter()
+            .map(|loaded| loaded.settings.clone())
+            .collect();
+        let mut old_watchers: Vec<Option<LoadedWatcher>> =
+            self.watchers.drain(..).map(Some).collect();
+
+        let mut changes = vec![];
+        for diff in config::diff_watchers(&old_settings, &new_watchers) {
+            match diff {
+                WatcherDiff::Removed { old } => {
+                    if let Some(loaded) = old_watchers[old].take() {
+                        fw.remove_watch(loaded.watch_id);
+                        changes.push(format!("removed watcher {:?}", loaded.settings.all_paths()));
+                    }
+                }
+                WatcherDiff::Unchanged { old, .. } if !defaults_changed => {
+                    if let Some(loaded) = old_watchers[old].take() {
+                        self.watchers.push(loaded);
+                    }
+                }
+                WatcherDiff::Unchanged { old, new } | WatcherDiff::Changed { old, new } => {
+                    let Some(loaded) = old_watchers[old].take() else {
+                        continue;
+                    };
+                    let watcher = &new_watchers[new];
+                    match update_watcher(
+                        fw,
+                        loaded.watch_id,
+                        &loaded.settings,
+                        watcher,
+                        &defaults,
+                        defaults_changed,
+                    ) {
+                        Ok(()) => {
+                            changes.push(format!("changed watcher {:?}", watcher.all_paths()));
+                            self.watchers.push(LoadedWatcher {
+                                settings: watcher.clone(),
+                                watch_id: loaded.watch_id,
+                            });
+                        }
+                        Err(error) => {
+                            println!("Error changing watcher {}: {}", new + 1, error);
+                            self.watchers.push(loaded);
+                        }
+                    }
+                }
+                WatcherDiff::Added { new } => {
+                    let watcher = &new_watchers[new];
+                    match add_watcher(fw, watcher, &defaults) {
+                        Ok(watch_id) => {
+                            changes.push(format!("added watcher {:?}", watcher.all_paths()));
+                            self.watchers.push(LoadedWatcher {
+                                settings: watcher.clone(),
+                                watch_id,
+                            });
+                        }
+                        Err(error) => println!("Error adding watcher {}: {}", new + 1, error),
+                    }
+                }
+            }
+        }
+        self.defaults = defaults;
+
+        if changes.is_empty() {
+            println!("Reloaded config file {:?}, no watchers changed.", self.path);
+        } else {
+            println!(
+                "Reloaded config file {:?}: {}.",
+                self.path,
+                changes.join(", ")
+            );
+        }
+    }
+}
+
+fn to_queue_policy(name: &str) -> QueuePolicy {
+    match QueuePolicy::from_name(name) {
+        Some(policy) => policy,
+        None => {
+            println!(
+                "Unknown on_busy policy: {}. Expected queue, drop-new or restart. Exiting.",
+                name
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn to_failure_policy(name: &str) -> FailurePolicy {
+    match FailurePolicy::from_name(name) {
+        Some(policy) => policy,
+        None => {
+            println!(
+                "Unknown on_failure policy: {}. Expected continue, stop or exit. Exiting.",
+                name
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn build_filter(include: &[String], exclude: &[String]) -> PathFilter {
+    match PathFilter::new(include, exclude) {
+        Ok(filter) => filter,
+        Err(error) => {
+            println!("Invalid glob pattern: {}. Exiting.", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn build_event_filter(names: &[String]) -> EventFilter {
+    match EventFilter::new(names) {
+        Ok(filter) => filter,
+        Err(name) => {
+            println!(
+                "Unknown event type: {}. Expected {}. Exiting.",
+                name, EVENT_TYPE_NAMES
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Finds the config file to use when eagle is started without a config file
+/// or paths to watch, or exits if there is none.
+fn discover_config() -> PathBuf {
+    let dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(error) => {
+            println!(
+                "Error: could not get the working directory: {}. Exiting.",
+                error
+            );
+            process::exit(1);
+        }
+    };
+
+    match config::find_config_file(&dir) {
+        Some(config_path) => {
+            println!("Using config file {:?}", config_path);
+            config_path
+        }
+        None => {
+            println!(
+                "No config file found in {:?} or its parents, and no user config file. \
+                 Pass --config, or -p and -e. Exiting.",
+                dir
+            );
+            process::exit(1);
+        }
+    }
+}
+
 // #[cfg_attr(test)
 fn main() {
     let cli = Cli::parse();
 
-    let mut actions: Vec<Box<dyn Action + 'static>> = vec![];
-    let mut fw = FilesWatcher::new();
+    // This has to happen before any threads are started.
+    if let Err(error) = signals::block_exit_signals() {
+        println!("Warning: could not handle signals: {}", error);
+    }
+
+    let mut fw = match FilesWatcher::try_new() {
+        Ok(fw) => fw,
+        Err(error) => {
+            println!("Error: {}. Exiting.", error);
+            process::exit(1);
+        }
+    };
+    let mut shutdown_timeout = Duration::from_millis(cli.shutdown_timeout);
+    let mut loaded_config = None;
 
-    if let Some(config_path) = cli.config.as_deref() {
-        let config = match config::parse_file(config_path) {
-            Some(config) => config,
-            None => {
-                println!("Error parsing config file. Exiting.");
+    let config_path = match cli.config {
+        Some(config_path) => Some(config_path),
+        None if cli.path.is_empty() && cli.execute.is_none() => Some(discover_config()),
+        None => None,
+    };
+
+    // With --once, changes after the first one are ignored, and eagle exits
+    // once the actions for it have finished. Without actions, the changed
+    // paths are printed.
+    fw.set_single_change(cli.once);
+    let prints_paths = cli.once && config_path.is_none() && cli.execute.is_none();
+    if let Some(config_path) = config_path {
+        let config = match config::parse_file(&config_path) {
+            Ok(config) => config,
+            Err(error) => {
+                println!("Error: {}. Exiting.", error);
                 process::exit(1);
             }
         };
 
-        let settings = match config.settings {
-            Some(settings) => settings,
-            None => SettingsConfig { quiet: Some(false) },
-        };
+        let defaults = ActionDefaults::from_config(&config);
+        let settings = config.settings.unwrap_or_default();
 
-        let quiet_flag = settings.quiet.unwrap_or(false);
+        if let Some(debounce_ms) = settings.debounce_ms {
+            fw.set_debounce(Duration::from_millis(debounce_ms));
+        }
+        fw.set_workers(settings.workers.unwrap_or(cli.workers));
+        if let Some(shutdown_timeout_ms) = settings.shutdown_timeout_ms {
+            shutdown_timeout = Duration::from_millis(shutdown_timeout_ms);
+        }
 
-        let watchers = match config.watchers {
-            Some(watchers) => watchers,
-            None => {
-                println!("No watchers defined in config file");
-                process::exit(1);
-            }
+        let mut loaded = LoadedConfig {
+            path: config_path,
+            defaults,
+            workers: settings.workers,
+            shutdown_timeout_ms: settings.shutdown_timeout_ms,
+            watchers: vec![],
+            watch_id: None,
         };
 
-        // TODO: convert to for loop to handle multiple watchers
-        if !watchers.is_empty() {
-            let watcher = &watchers[0];
-
-            if let "command" = watcher.action_type.as_ref() {
-                let execute_string = watcher.execute.to_owned();
-                let command = CommandAction::new(execute_string, quiet_flag);
-                actions.push(Box::new(command));
+        // Watchers are added in the order they appear in the config file,
+        // which is also the order their actions run in when several of them
+        // are due at the same time. Parsing validated the config, so there
+        // is at least one watcher.
+        for (index, watcher) in config.watchers.unwrap_or_default().into_iter().enumerate() {
+            match add_watcher(&mut fw, &watcher, &loaded.defaults) {
+                Ok(watch_id) => loaded.watchers.push(LoadedWatcher {
+                    settings: watcher,
+                    watch_id,
+                }),
+                Err(error) => {
+                    println!("Error adding watcher {}: {}. Exiting.", index + 1, error);
+                    // Stops the processes of the watchers added before.
+                    fw.shutdown(shutdown_timeout);
+                    process::exit(1);
+                }
             }
+        }
 
-            let path_buf = PathBuf::from(watcher.path.to_owned());
-            fw.add_file(path_buf, actions);
+        // There is nothing to reload for a single change.
+        if !cli.once {
+            loaded.watch(&mut fw);
         }
+        loaded_config = Some(loaded);
     } else {
+        fw.set_workers(cli.workers);
+
+        let mut actions: Vec<Box<dyn Action + 'static>> = vec![];
         let flag_quiet = cli.quiet;
-        if !flag_quiet {
+        if !flag_quiet && !prints_paths {
             let print = PrintAction::new();
             actions.push(Box::new(print));
         }
 
         if let Some(execute) = cli.execute.as_deref() {
-            let command = CommandAction::new(execute.to_string(), flag_quiet);
-            actions.push(Box::new(command));
+            let mut command = match CommandAction::new(execute.to_string(), flag_quiet) {
+                Ok(command) => command,
+                Err(error) => {
+                    println!("Error: {}. Exiting.", error);
+                    process::exit(1);
+                }
+            };
+            command.set_shell(match cli.shell {
+                None => Shell::None,
+                Some(None) => Shell::Default,
+                Some(Some(program)) => Shell::Program(program),
+            });
+            if let Err(error) = command.validate() {
+                println!("Error: {}. Exiting.", error);
+                process::exit(1);
+            }
+            if let Some(timeout_ms) = cli.command_timeout {
+                command.set_timeout(Duration::from_millis(timeout_ms));
+            }
+            command.set_grace_period(Duration::from_millis(cli.grace));
+
+            if cli.restart {
+                let signal = match restart::parse_signal(&cli.signal) {
+                    Some(signal) => signal,
+                    None => {
+                        println!("Unknown signal: {}. Exiting.", cli.signal);
+                        process::exit(1);
+                    }
+                };
+                let restart = build_restart(command, Some(signal), Some(cli.grace), flag_quiet);
+                actions.push(Box::new(restart));
+            } else {
+                actions.push(Box::new(command));
+            }
+        }
+
+        if !cli.path.is_empty() {
+            let options = WatchOptions {
+                recursive: cli.recursive,
+                filter: build_filter(&cli.include, &cli.exclude),
+                events: build_event_filter(&cli.events),
+                respect_gitignore: cli.gitignore,
+                debounce: cli.debounce.map(Duration::from_millis),
+                max_concurrent: Some(cli.max_concurrent),
+                queue_policy: to_queue_policy(&cli.on_busy),
+                on_failure: to_failure_policy(&cli.on_failure),
+                run_on_start: cli.initial_run,
+            };
+            if let Err(error) = fw.add_watch(cli.path, options, actions) {
+                println!("Error adding watch: {}. Exiting.", error);
+                fw.shutdown(shutdown_timeout);
+                process::exit(1);
+            }
         }
+    }
 
-        if let Some(path) = cli.path.as_deref() {
-            fw.add_file(path.to_path_buf(), actions);
+    // The first signal asks the watcher to stop. Later ones are ignored, as
+    // the shutdown timeout already limits how long stopping takes.
+    let received_signal = Arc::new(AtomicI32::new(0));
+    let stop_handle = fw.stop_handle();
+    let signal = received_signal.clone();
+    signals::on_exit_signal(move |number| {
+        if signal
+            .compare_exchange(0, number, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            stop_handle.stop();
         }
+    });
+
+    if !cli.once {
+        fw.queue_start_runs();
     }
 
-    loop {
-        let result = fw.wait_and_execute();
+    let deadline = cli
+        .timeout
+        .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+    let exit_code = loop {
+        let result = match deadline {
+            Some(deadline) => fw.wait_and_execute_until(deadline),
+            None => fw.wait_and_execute(),
+        };
 
         match result {
             Ok(execution_result) => {
+                if execution_result.stopped {
+                    println!(
+                        "Stopping, waiting up to {:?} for running actions.",
+                        shutdown_timeout
+                    );
+                    break 128 + received_signal.load(Ordering::SeqCst);
+                }
+                if execution_result.timed_out {
+                    println!("Nothing changed within {}ms.", cli.timeout.unwrap_or(0));
+                    break TIMEOUT_EXIT_CODE;
+                }
+
+                if let Some(loaded) = loaded_config.as_mut() {
+                    if execution_result.watch_id.is_some()
+                        && execution_result.watch_id == loaded.watch_id
+                    {
+                        loaded.reload(&mut fw);
+                        continue;
+                    }
+                }
+
+                if prints_paths && execution_result.watch_id.is_some() {
+                    for path in &execution_result.paths {
+                        println!("{}", path.display());
+                    }
+                    break 0;
+                }
+
                 if execution_result.was_file_changed {
                     println!(
                         "Executed {} action(s) successfully.",
                         execution_result.num_actions
                     );
                 }
+                for outcome in execution_result.failures() {
+                    if let Some(error) = &outcome.error {
+                        println!(
+                            "Action {} of watcher {:?} failed after {:.1}s: {}",
+                            outcome.action_index + 1,
+                            fw.paths_of(outcome.watch_id),
+                            outcome.duration.as_secs_f64(),
+                            error
+                        );
+                    }
+                }
+                if let Some(exit_code) = execution_result.exit_code {
+                    println!("Exiting because an action failed.");
+                    break exit_code;
+                }
+                if cli.once && execution_result.watch_id.is_some() {
+                    break execution_result
+                        .failures()
+                        .find_map(|outcome| outcome.error.as_ref())
+                        .map_or(0, |error| error.exit_code());
+                }
             }
-            Err(_) => println!("Error executing some actions."),
+            Err(error) => println!("Error executing some actions: {}", error),
         }
+    };
+
+    if !fw.shutdown(shutdown_timeout) {
+        println!(
+            "Running actions did not finish within {:?} and were stopped.",
+            shutdown_timeout
+        );
     }
+    process::exit(exit_code);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::thread;
 
-    // Uncomment this as soon as we have a way of leaving the loop
-    // fw.close();
+    #[cfg(unix)]
+    #[test]
+    fn keep_running_actions_if_reloaded_ones_cannot_start() {
+        let dir = env::temp_dir().join(format!("eagleeye-test-reload-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("started");
+        let config_path = dir.join("eagle.toml");
+        let config_content = |execute: &str| {
+            format!(
+                "[[watchers]]\npath = {:?}\naction_type = \"restart\"\nexecute = {:?}\n",
+                dir.join("src"),
+                execute
+            )
+        };
+        let started = || {
+            fs::read_to_string(&marker)
+                .unwrap_or_default()
+                .lines()
+                .count()
+        };
+        let execute = format!("sh -c 'echo >> {}; exec sleep 30'", marker.display());
+        fs::write(&config_path, config_content(&execute)).unwrap();
+
+        let config = config::parse_file(&config_path).unwrap();
+        let defaults = ActionDefaults::from_config(&config);
+        let settings = config.watchers.unwrap().remove(0);
+        let mut fw = FilesWatcher::new();
+        let watch_id = add_watcher(&mut fw, &settings, &defaults).unwrap();
+        let mut loaded = LoadedConfig {
+            path: config_path.clone(),
+            defaults,
+            workers: None,
+            shutdown_timeout_ms: None,
+            watchers: vec![LoadedWatcher {
+                settings: settings.clone(),
+                watch_id,
+            }],
+            watch_id: None,
+        };
+
+        let wait_for_starts = |count: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while started() < count && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(count, started());
+        };
+        wait_for_starts(1);
+
+        fs::write(&config_path, config_content("/nonexistent/eagle-server")).unwrap();
+        loaded.reload(&mut fw);
+        assert_eq!(settings, loaded.watchers[0].settings);
+
+        // The previous process was stopped for the new one and started again.
+        wait_for_starts(2);
+
+        fw.shutdown(Duration::ZERO);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
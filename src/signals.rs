@@ -0,0 +1,69 @@
+use std::io;
+
+/// Blocks SIGINT, SIGTERM and SIGHUP on the calling thread, so they don't
+/// terminate eagle and can be waited for with `on_exit_signal` instead. That
+/// way, eagle can stop running actions and the processes they started
+/// before it exits. Threads inherit the blocked signals, so this must be
+/// called before any other thread is started.
+#[cfg(unix)]
+pub fn block_exit_signals() -> io::Result<()> {
+    set_exit_signals_mask(libc::SIG_BLOCK)
+}
+
+/// Unblocks the signals blocked by `block_exit_signals`. Child processes
+/// inherit blocked signals, so this is called in them before they run their
+/// command. It is async-signal-safe.
+#[cfg(unix)]
+pub fn unblock_exit_signals() -> io::Result<()> {
+    set_exit_signals_mask(libc::SIG_UNBLOCK)
+}
+
+/// Calls `on_signal` on a thread of its own with the number of each signal
+/// blocked by `block_exit_signals` that eagle receives.
+#[cfg(unix)]
+pub fn on_exit_signal<F>(on_signal: F)
+where
+    F: Fn(i32) + Send + 'static,
+{
+    let signals = exit_signals();
+    std::thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } == 0 {
+            on_signal(signal);
+        }
+    });
+}
+
+#[cfg(unix)]
+fn set_exit_signals_mask(how: i32) -> io::Result<()> {
+    let signals = exit_signals();
+    match unsafe { libc::pthread_sigmask(how, &signals, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error)),
+    }
+}
+
+#[cfg(unix)]
+fn exit_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::sigaddset(&mut signals, signal);
+        }
+        signals
+    }
+}
+
+/// On platforms without signals, Ctrl-C terminates eagle right away.
+#[cfg(not(unix))]
+pub fn block_exit_signals() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn on_exit_signal<F>(_on_signal: F)
+where
+    F: Fn(i32) + Send + 'static,
+{
+}
@@ -0,0 +1,654 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::actions::{Action, CancelToken, Change, Execution};
+use crate::error::Error;
+
+/// What happens to a change for a watch whose actions are already running
+/// as often as its concurrency limit allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum QueuePolicy {
+    /// Run the actions once a running batch has finished. While a batch is
+    /// waiting, further changes are merged into it, so at most one batch
+    /// per watch waits at a time.
+    #[default]
+    Queue,
+    /// Drop the new change.
+    DropNew,
+    /// Cancel the running actions and run them again for the new change.
+    Restart,
+}
+
+impl QueuePolicy {
+    /// Returns the policy with a name like "drop-new", or `None` if the name
+    /// isn't known.
+    pub fn from_name(name: &str) -> Option<QueuePolicy> {
+        match name {
+            "queue" => Some(QueuePolicy::Queue),
+            "drop-new" => Some(QueuePolicy::DropNew),
+            "restart" => Some(QueuePolicy::Restart),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to the remaining actions of a change when one of them
+/// fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FailurePolicy {
+    /// Run the remaining actions anyway.
+    #[default]
+    Continue,
+    /// Skip the remaining actions for this change.
+    Stop,
+    /// Skip the remaining actions and ask for eagle to exit, with the exit
+    /// code of the failed command.
+    Exit,
+}
+
+impl FailurePolicy {
+    /// Returns the policy with a name like "stop", or `None` if the name
+    /// isn't known.
+    pub fn from_name(name: &str) -> Option<FailurePolicy> {
+        match name {
+            "continue" => Some(FailurePolicy::Continue),
+            "stop" => Some(FailurePolicy::Stop),
+            "exit" => Some(FailurePolicy::Exit),
+            _ => None,
+        }
+    }
+}
+
+/// The actions of a watch, run once for a change.
+pub struct Job {
+    pub watch_id: usize,
+    pub change: Change,
+    pub actions: Arc<Vec<Box<dyn Action>>>,
+    /// How many jobs of the same watch may run at the same time.
+    pub max_concurrent: usize,
+    pub policy: QueuePolicy,
+    pub on_failure: FailurePolicy,
+}
+
+/// What happened when an action ran for a change.
+#[derive(Debug)]
+pub struct ActionOutcome {
+    pub watch_id: usize,
+    /// The position of the action in the list of actions of its watch.
+    pub action_index: usize,
+    /// What the action does, as returned by `Action::describe`.
+    pub action: String,
+    /// The changed paths the action ran for.
+    pub paths: Vec<PathBuf>,
+    pub duration: Duration,
+    /// The exit status of the process the action ran, if it ran one.
+    pub exit_status: Option<ExitStatus>,
+    /// The end of what the process wrote to stdout, at most
+    /// `actions::OUTPUT_LIMIT` bytes.
+    pub stdout: String,
+    /// The end of what the process wrote to stderr, at most
+    /// `actions::OUTPUT_LIMIT` bytes.
+    pub stderr: String,
+    /// Why the action failed or was cancelled, if it did not succeed.
+    pub error: Option<Error>,
+}
+
+impl ActionOutcome {
+    /// Returns true if the action failed. Cancelled actions don't count as
+    /// failed.
+    pub fn is_failure(&self) -> bool {
+        !matches!(self.error, None | Some(Error::Cancelled { .. }))
+    }
+}
+
+/// What happened when a job ran.
+#[derive(Debug, Default)]
+pub struct JobResult {
+    pub watch_id: usize,
+    /// The changed paths the actions ran for.
+    pub paths: Vec<PathBuf>,
+    /// The number of actions that ran successfully.
+    pub num_actions: usize,
+    /// The number of actions that were stopped because they ran for longer
+    /// than their timeout. They also count as failed.
+    pub num_timed_out: usize,
+    /// The outcomes of the actions that ran, in the order they ran.
+    pub outcomes: Vec<ActionOutcome>,
+    /// Set if an action failed and the failure policy is
+    /// `FailurePolicy::Exit`.
+    pub exit_code: Option<i32>,
+    pub cancelled: bool,
+}
+
+struct RunningJob {
+    id: usize,
+    watch_id: usize,
+    cancel: CancelToken,
+}
+
+#[derive(Default)]
+struct State {
+    waiting: VecDeque<Job>,
+    running: Vec<RunningJob>,
+    next_id: usize,
+    shutdown: bool,
+}
+
+impl State {
+    fn num_running(&self, watch_id: usize) -> usize {
+        self.running
+            .iter()
+            .filter(|job| job.watch_id == watch_id)
+            .count()
+    }
+
+    /// Takes the first waiting job whose watch is below its concurrency
+    /// limit.
+    fn next_runnable(&mut self) -> Option<Job> {
+        let index = self
+            .waiting
+            .iter()
+            .position(|job| self.num_running(job.watch_id) < job.max_concurrent.max(1))?;
+        self.waiting.remove(index)
+    }
+}
+
+type FinishedCallback = dyn Fn(JobResult) + Send + Sync;
+
+/// Runs the actions of watches on a fixed number of worker threads, so the
+/// thread receiving file events is never blocked by a slow action.
+///
+/// The jobs of each watch are limited to its `max_concurrent` setting, and
+/// its `QueuePolicy` decides what happens to changes that arrive while it's
+/// at the limit. Jobs become runnable in the order they were submitted.
+pub struct WorkerPool {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Starts a pool with `num_workers` threads, at least one. The callback
+    /// is called on a worker thread after each job has finished.
+    pub fn new<F>(num_workers: usize, on_finished: F) -> WorkerPool
+    where
+        F: Fn(JobResult) + Send + Sync + 'static,
+    {
+        let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let on_finished: Arc<FinishedCallback> = Arc::new(on_finished);
+
+        let workers = (0..num_workers.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                let on_finished = on_finished.clone();
+                thread::spawn(move || work(&shared, on_finished.as_ref()))
+            })
+            .collect();
+
+        WorkerPool { shared, workers }
+    }
+
+    /// Submits a job, applying its queue policy if its watch is busy.
+    /// Returns false if the job was dropped.
+    pub fn submit(&self, job: Job) -> bool {
+        let (state, condvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+
+        let waiting = state
+            .waiting
+            .iter()
+            .position(|waiting| waiting.watch_id == job.watch_id);
+
+        match job.policy {
+            QueuePolicy::Queue => {
+                if let Some(index) = waiting {
+                    state.waiting[index].change.merge(job.change);
+                    return true;
+                }
+            }
+            QueuePolicy::DropNew => {
+                let num_waiting = state
+                    .waiting
+                    .iter()
+                    .filter(|waiting| waiting.watch_id == job.watch_id)
+                    .count();
+                if state.num_running(job.watch_id) + num_waiting >= job.max_concurrent.max(1) {
+                    return false;
+                }
+            }
+            QueuePolicy::Restart => {
+                for running in state.running.iter() {
+                    if running.watch_id == job.watch_id {
+                        running.cancel.cancel();
+                    }
+                }
+                // The paths of a batch that never got to run are not lost.
+                if let Some(index) = waiting {
+                    let mut earlier = state.waiting.remove(index).unwrap();
+                    earlier.change.merge(job.change);
+                    state.waiting.push_back(Job {
+                        change: earlier.change,
+                        ..job
+                    });
+                    condvar.notify_all();
+                    return true;
+                }
+            }
+        }
+
+        state.waiting.push_back(job);
+        condvar.notify_all();
+
+        true
+    }
+
+    /// Stops the pool, dropping the waiting jobs. The running jobs get up to
+    /// `timeout` to finish, and are cancelled if they haven't by then.
+    /// Returns true if they all finished in time.
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (state, condvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.shutdown = true;
+        state.waiting.clear();
+        condvar.notify_all();
+
+        while !state.running.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = condvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        // Dropping the pool cancels the jobs that are still running.
+        state.running.is_empty()
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Cancels the running jobs, drops the waiting ones and waits for the
+    /// workers to exit.
+    fn drop(&mut self) {
+        {
+            let (state, condvar) = &*self.shared;
+            let mut state = state.lock().unwrap();
+            state.shutdown = true;
+            state.waiting.clear();
+            for running in state.running.iter() {
+                running.cancel.cancel();
+            }
+            condvar.notify_all();
+        }
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(shared: &(Mutex<State>, Condvar), on_finished: &FinishedCallback) {
+    let (state, condvar) = shared;
+    let mut guard = state.lock().unwrap();
+
+    loop {
+        if guard.shutdown {
+            return;
+        }
+
+        let job = match guard.next_runnable() {
+            Some(job) => job,
+            None => {
+                guard = condvar.wait(guard).unwrap();
+                continue;
+            }
+        };
+
+        let id = guard.next_id;
+        guard.next_id += 1;
+        let cancel = CancelToken::new();
+        guard.running.push(RunningJob {
+            id,
+            watch_id: job.watch_id,
+            cancel: cancel.clone(),
+        });
+        drop(guard);
+
+        let result = run_actions(
+            job.watch_id,
+            &job.actions,
+            &job.change,
+            job.on_failure,
+            &cancel,
+        );
+
+        guard = state.lock().unwrap();
+        guard.running.retain(|running| running.id != id);
+        condvar.notify_all();
+        drop(guard);
+
+        on_finished(result);
+        guard = state.lock().unwrap();
+    }
+}
+
+/// Runs the actions of a watch in order for a change, stopping early if
+/// `cancel` is cancelled or an action fails and `on_failure` says so.
+pub fn run_actions(
+    watch_id: usize,
+    actions: &[Box<dyn Action>],
+    change: &Change,
+    on_failure: FailurePolicy,
+    cancel: &CancelToken,
+) -> JobResult {
+    let mut result = JobResult {
+        watch_id,
+        paths: change.paths.clone(),
+        ..Default::default()
+    };
+    for (action_index, action) in actions.iter().enumerate() {
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        let started = Instant::now();
+        let mut execution = Execution::new(cancel.clone());
+        let error = action.execute(change, &mut execution).err();
+        let outcome = ActionOutcome {
+            watch_id,
+            action_index,
+            action: action.describe(),
+            paths: change.paths.clone(),
+            duration: started.elapsed(),
+            exit_status: execution.exit_status,
+            stdout: execution.stdout,
+            stderr: execution.stderr,
+            error,
+        };
+
+        let is_failure = outcome.is_failure();
+        match outcome.error {
+            None => result.num_actions += 1,
+            Some(Error::Timeout { .. }) => result.num_timed_out += 1,
+            Some(_) => {}
+        }
+        if is_failure && on_failure == FailurePolicy::Exit {
+            result.exit_code = outcome.error.as_ref().map(Error::exit_code);
+        }
+        result.outcomes.push(outcome);
+
+        if is_failure && on_failure != FailurePolicy::Continue {
+            break;
+        }
+    }
+    result.cancelled = cancel.is_cancelled();
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::error::Result;
+    use notify::EventKind;
+    use std::path::PathBuf;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    /// The paths of the changes a `SlowAction` finished handling.
+    type Finished = Arc<Mutex<Vec<Vec<PathBuf>>>>;
+
+    /// Sleeps for a while unless cancelled, recording the changes it
+    /// finished handling.
+    struct SlowAction {
+        finished: Finished,
+    }
+
+    impl Action for SlowAction {
+        fn handle_change(&self, change: &Change) -> Result<()> {
+            self.execute(change, &mut Execution::default())
+        }
+
+        fn execute(&self, change: &Change, execution: &mut Execution) -> Result<()> {
+            for _ in 0..20 {
+                if execution.cancel.is_cancelled() {
+                    return Err(Error::Cancelled {
+                        command: "sleep".to_string(),
+                    });
+                }
+                sleep(Duration::from_millis(10));
+            }
+            self.finished.lock().unwrap().push(change.paths.clone());
+            Ok(())
+        }
+    }
+
+    fn start_pool(num_workers: usize) -> (WorkerPool, Receiver<JobResult>) {
+        let (tx, rx) = mpsc::channel();
+        let pool = WorkerPool::new(num_workers, move |result| {
+            let _ = tx.send(result);
+        });
+        (pool, rx)
+    }
+
+    fn job(
+        actions: &Arc<Vec<Box<dyn Action>>>,
+        path: &str,
+        max_concurrent: usize,
+        policy: QueuePolicy,
+    ) -> Job {
+        Job {
+            watch_id: 0,
+            change: Change::new(
+                EventKind::Any,
+                vec![PathBuf::from(path)],
+                PathBuf::from("/"),
+            ),
+            actions: actions.clone(),
+            max_concurrent,
+            policy,
+            on_failure: FailurePolicy::Continue,
+        }
+    }
+
+    fn slow_actions() -> (Arc<Vec<Box<dyn Action>>>, Finished) {
+        let finished = Arc::new(Mutex::new(vec![]));
+        let action = SlowAction {
+            finished: finished.clone(),
+        };
+        let actions: Vec<Box<dyn Action>> = vec![Box::new(action)];
+        (Arc::new(actions), finished)
+    }
+
+    #[test]
+    fn queue_merges_waiting_changes() {
+        let (pool, rx) = start_pool(4);
+        let (actions, finished) = slow_actions();
+
+        assert!(pool.submit(job(&actions, "/a", 1, QueuePolicy::Queue)));
+        // Let the first job start, so the others have to wait for it.
+        sleep(Duration::from_millis(50));
+        for path in ["/b", "/c"] {
+            assert!(pool.submit(job(&actions, path, 1, QueuePolicy::Queue)));
+        }
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+
+        let finished = finished.lock().unwrap();
+        assert_eq!(
+            vec![
+                vec![PathBuf::from("/a")],
+                vec![PathBuf::from("/b"), PathBuf::from("/c")]
+            ],
+            *finished
+        );
+    }
+
+    #[test]
+    fn shutdown_waits_for_running_jobs() {
+        let (pool, _rx) = start_pool(4);
+        let (actions, finished) = slow_actions();
+
+        assert!(pool.submit(job(&actions, "/a", 1, QueuePolicy::Queue)));
+        sleep(Duration::from_millis(50));
+        assert!(pool.submit(job(&actions, "/b", 1, QueuePolicy::Queue)));
+
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(vec![vec![PathBuf::from("/a")]], *finished.lock().unwrap());
+    }
+
+    #[test]
+    fn shutdown_cancels_jobs_after_timeout() {
+        let (pool, rx) = start_pool(4);
+        let (actions, finished) = slow_actions();
+
+        assert!(pool.submit(job(&actions, "/a", 1, QueuePolicy::Queue)));
+        sleep(Duration::from_millis(50));
+
+        assert!(!pool.shutdown(Duration::from_millis(20)));
+        assert!(rx.recv().unwrap().cancelled);
+        assert!(finished.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn drop_new_changes_while_busy() {
+        let (pool, rx) = start_pool(4);
+        let (actions, finished) = slow_actions();
+
+        assert!(pool.submit(job(&actions, "/a", 1, QueuePolicy::DropNew)));
+        assert!(!pool.submit(job(&actions, "/b", 1, QueuePolicy::DropNew)));
+        rx.recv().unwrap();
+
+        assert_eq!(vec![vec![PathBuf::from("/a")]], *finished.lock().unwrap());
+    }
+
+    #[test]
+    fn restart_cancels_running_actions() {
+        let (pool, rx) = start_pool(4);
+        let (actions, finished) = slow_actions();
+
+        assert!(pool.submit(job(&actions, "/a", 1, QueuePolicy::Restart)));
+        sleep(Duration::from_millis(50));
+        assert!(pool.submit(job(&actions, "/b", 1, QueuePolicy::Restart)));
+
+        let first = rx.recv().unwrap();
+        assert!(first.cancelled);
+        assert_eq!(0, first.num_actions);
+        let second = rx.recv().unwrap();
+        assert!(!second.cancelled);
+        assert_eq!(1, second.num_actions);
+
+        assert_eq!(vec![vec![PathBuf::from("/b")]], *finished.lock().unwrap());
+    }
+
+    #[test]
+    fn run_jobs_concurrently_up_to_the_limit() {
+        let (pool, rx) = start_pool(4);
+        let (actions, finished) = slow_actions();
+
+        for path in ["/a", "/b"] {
+            assert!(pool.submit(job(&actions, path, 2, QueuePolicy::DropNew)));
+        }
+        assert!(!pool.submit(job(&actions, "/c", 2, QueuePolicy::DropNew)));
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+
+        assert_eq!(2, finished.lock().unwrap().len());
+    }
+
+    #[test]
+    fn queue_policy_names() {
+        assert_eq!(Some(QueuePolicy::Queue), QueuePolicy::from_name("queue"));
+        assert_eq!(
+            Some(QueuePolicy::DropNew),
+            QueuePolicy::from_name("drop-new")
+        );
+        assert_eq!(
+            Some(QueuePolicy::Restart),
+            QueuePolicy::from_name("restart")
+        );
+        assert_eq!(None, QueuePolicy::from_name("drop"));
+    }
+
+    /// Fails with the exit status of `sh -c 'exit 3'`.
+    struct FailingAction;
+
+    impl Action for FailingAction {
+        fn handle_change(&self, _change: &Change) -> Result<()> {
+            let status = std::process::Command::new("sh")
+                .args(["-c", "exit 3"])
+                .status()
+                .unwrap();
+            Err(Error::ActionExitStatus {
+                command: "exit 3".to_string(),
+                status,
+            })
+        }
+    }
+
+    fn run_failing(on_failure: FailurePolicy) -> (JobResult, Finished) {
+        let finished = Arc::new(Mutex::new(vec![]));
+        let actions: Vec<Box<dyn Action>> = vec![
+            Box::new(FailingAction),
+            Box::new(SlowAction {
+                finished: finished.clone(),
+            }),
+        ];
+
+        let change = Change::new(
+            EventKind::Any,
+            vec![PathBuf::from("/a")],
+            PathBuf::from("/"),
+        );
+        let result = run_actions(0, &actions, &change, on_failure, &CancelToken::new());
+        (result, finished)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn continue_after_failure() {
+        let (result, finished) = run_failing(FailurePolicy::Continue);
+        assert_eq!(1, result.num_actions);
+        assert_eq!(2, result.outcomes.len());
+        assert!(result.outcomes[0].is_failure());
+        assert!(!result.outcomes[1].is_failure());
+        assert_eq!(1, result.outcomes[1].action_index);
+        assert_eq!(None, result.exit_code);
+        assert_eq!(1, finished.lock().unwrap().len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_after_failure() {
+        let (result, finished) = run_failing(FailurePolicy::Stop);
+        assert_eq!(0, result.num_actions);
+        assert_eq!(1, result.outcomes.len());
+        assert!(result.outcomes[0].is_failure());
+        assert_eq!(None, result.exit_code);
+        assert!(finished.lock().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_after_failure() {
+        let (result, finished) = run_failing(FailurePolicy::Exit);
+        assert_eq!(Some(3), result.exit_code);
+        assert!(finished.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failure_policy_names() {
+        assert_eq!(
+            Some(FailurePolicy::Continue),
+            FailurePolicy::from_name("continue")
+        );
+        assert_eq!(Some(FailurePolicy::Stop), FailurePolicy::from_name("stop"));
+        assert_eq!(Some(FailurePolicy::Exit), FailurePolicy::from_name("exit"));
+        assert_eq!(None, FailurePolicy::from_name("abort"));
+    }
+}